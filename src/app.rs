@@ -1,21 +1,19 @@
 //! The main application logic.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{anyhow, Result};
-use cargo::GlobalContext;
-use cargo::{
-    core::Workspace,
-    ops::CompileOptions,
-    util::{important_paths, interning::InternedString},
-};
+use cargo_metadata::Artifact;
 
-use crate::instruments;
-use crate::opt::{AppConfig, CargoOpts, Target};
+use crate::instruments::{self, ProfileRun, TraceMetadata};
+use crate::opt::{self, AppConfig, CargoOpts, CliFeatures, Package, Target};
+use crate::shell::Shell;
+use crate::workspace::{self, Workspace};
 
 /// Main entrance point, after args have been parsed.
-pub(crate) fn run(app_config: AppConfig) -> Result<()> {
+pub(crate) fn run(mut app_config: AppConfig) -> Result<()> {
     // 1. Detect the type of Xcode Instruments installation
     let xctrace_tool = instruments::XcodeInstruments::detect()?;
     log::debug!("using {xctrace_tool}");
@@ -27,78 +25,831 @@ pub(crate) fn run(app_config: AppConfig) -> Result<()> {
         return Ok(());
     }
 
-    // 3. Build the specified target
-    let cargo_config = GlobalContext::default()?;
-
-    let manifest_path = match app_config.manifest_path.as_ref() {
-        Some(path) if path.is_absolute() => Ok(path.to_owned()),
-        Some(path) => Ok(cargo_config.cwd().join(path)),
-        None => important_paths::find_root_manifest_for_wd(cargo_config.cwd()),
-    }?;
-
-    log::debug!("using cargo manifest at {}", manifest_path.display());
+    // 2.1: Check the instruments to record, if any were given
+    if !app_config.instrument.is_empty() {
+        xctrace_tool.check_instruments(&app_config.instrument)?;
+    }
 
-    let workspace = Workspace::new(&manifest_path, &cargo_config)?;
+    // 3. Build the specified target
+    let mut cargo_options = app_config.to_cargo_opts()?;
+    let shell = Shell::new(cargo_options.color.as_deref(), cargo_options.quiet);
 
-    // 3.1: warn if --open passed. We do this here so we have access to cargo's
+    // 3.1: warn if --open passed. We do this here so we have access to the
     // pretty-printer
     if app_config.open {
-        workspace
-            .gctx()
-            .shell()
-            .warn("--open is now the default behaviour, and will be ignored.")?;
+        shell.warn("--open is now the default behaviour, and will be ignored.")?;
+    }
+
+    // 3.2: read the target's environment, with `--env` taking precedence
+    if !app_config.env_file.is_empty() {
+        match read_env_files(&app_config.env_file) {
+            Ok(mut env) => {
+                merge_env(&mut env, app_config.env.drain(..));
+                app_config.env = env;
+            }
+            Err(e) => {
+                shell.error(&e)?;
+                return Err(e);
+            }
+        }
     }
 
-    let cargo_options = app_config.to_cargo_opts()?;
+    // 3.3: profile the build itself instead of the targets, if asked
+    if app_config.profile_build {
+        return profile_build(&mut cargo_options, &xctrace_tool, &app_config, &shell);
+    }
 
-    log::debug!("building profile target {}", cargo_options.target);
-    let target_filepath = match build_target(&cargo_options, &workspace) {
-        Ok(path) => path,
+    // A prebuilt executable doesn't need a workspace, but if we are in one, its
+    // traces go in the same place as usual.
+    let built_targets = match app_config.exec {
+        Some(ref exec_path) => BuiltTarget::prebuilt(exec_path, &app_config).map(|built_target| {
+            let manifest_path = app_config.manifest_path.as_deref();
+            (Workspace::new(&cargo_options, manifest_path).ok(), vec![built_target])
+        }),
+        None => build_workspace_targets(&mut cargo_options, &app_config, &shell)
+            .map(|(workspace, built_targets)| (Some(workspace), built_targets)),
+    };
+    let (workspace, built_targets) = match built_targets {
+        Ok(built_targets) => built_targets,
         Err(e) => {
-            workspace.gctx().shell().error(&e)?;
+            shell.error(&e)?;
             return Err(e);
         }
     };
+    let workspace = workspace.as_ref();
 
-    log::debug!("running against target {}", target_filepath.display());
+    if built_targets.len() > 1 {
+        let flag = if app_config.trace_filepath.is_some() {
+            Some("--output")
+        } else if app_config.bench_id.is_some() {
+            Some("--bench-id")
+        } else if app_config.attach.is_some() {
+            Some("--attach")
+        } else {
+            None
+        };
+        if let Some(flag) = flag {
+            let e = anyhow!("{flag} can only be used when profiling a single target");
+            shell.error(&e)?;
+            return Err(e);
+        }
+    }
+    if app_config.attach.is_some() && !app_config.target_args.is_empty() {
+        shell.warn("arguments for the target are ignored with --attach")?;
+    }
 
-    #[cfg(target_arch = "aarch64")]
-    codesign(&target_filepath, &workspace)?;
+    // Traces go in the `instruments` directory of the target directory, next
+    // to the artifacts they were recorded from, or in a directory of their own
+    // for each run when profiling the whole workspace. Outside of a workspace,
+    // they go in `instruments` in the current directory.
+    let mut trace_dir = match workspace {
+        Some(workspace) => workspace.target_dir().join("instruments"),
+        None => std::env::current_dir()?.join("instruments"),
+    };
+    if app_config.workspace {
+        let now = chrono::Local::now();
+        trace_dir.push(format!("run_{}", now.format("%F_%H%M%S")));
+    }
 
-    // 4. Profile the built target, will display menu if no template was selected
-    let trace_filepath =
-        match instruments::profile_target(&target_filepath, &xctrace_tool, &app_config, &workspace)
+    // 4. Profile each built target in turn, will display menu if no template was selected
+    let mut traces = Vec::new();
+    let mut any_failed = false;
+    for built_target in &built_targets {
+        // Ctrl-C stops the whole run, not just the current recording.
+        if instruments::was_interrupted() {
+            break;
+        }
+        log::debug!("running against target {}", built_target.path.display());
+
+        // The host of a cdylib isn't ours to resign, nor is a running process.
+        if built_target.target != Target::Cdylib
+            && app_config.attach.is_none()
+            && needs_codesign(cargo_options.target_triple.as_deref())
         {
-            Ok(path) => path,
+            codesign(&built_target.path, &shell)?;
+        }
+
+        // 4a. Profile each test in the harness separately, if asked
+        if app_config.each_test {
+            let (test_traces, failed) = profile_each_test(
+                built_target,
+                &trace_dir,
+                &xctrace_tool,
+                &app_config,
+                &shell,
+                workspace,
+            )?;
+            traces.extend(test_traces);
+            any_failed |= failed;
+        } else {
+            let (trace_filepath, failed) = profile_built_target(
+                built_target,
+                &trace_dir,
+                &xctrace_tool,
+                &app_config,
+                &shell,
+                workspace,
+            )?;
+            let name = match app_config.workspace {
+                true => format!("{}: {}", built_target.package, built_target.target),
+                false => built_target.target.to_string(),
+            };
+            traces.push((name, trace_filepath));
+            any_failed |= failed;
+        }
+    }
+
+    // 5. Summarize the trace files, if there are several of them
+    if traces.len() > 1 {
+        println!("{}", render_trace_summary(&traces, workspace.map(Workspace::root)));
+    } else if let [(_, Some(trace_filepath))] = traces.as_slice() {
+        // 6. Open Xcode Instruments if asked
+        if !app_config.no_open {
+            launch_instruments(trace_filepath)?;
+        }
+    }
+
+    // A recording that failed always fails the run, once the others are done.
+    let failed_recordings = traces.iter().filter(|(_, trace)| trace.is_none()).count();
+    if failed_recordings > 0 {
+        let e = anyhow!("failed to record {} of {} traces", failed_recordings, traces.len());
+        shell.error(&e)?;
+        return Err(e);
+    }
+    check_target_errors(&app_config, any_failed, &shell)
+}
+
+/// With `--fail-on-target-error`, fail if any profiled target exited with an
+/// error.
+fn check_target_errors(app_config: &AppConfig, any_failed: bool, shell: &Shell) -> Result<()> {
+    if app_config.fail_on_target_error && any_failed {
+        let e = anyhow!("a profiled target exited with an error");
+        shell.error(&e)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Load the cargo workspace and build the targets selected by `cargo_options`.
+fn build_workspace_targets(
+    cargo_options: &mut CargoOpts,
+    app_config: &AppConfig,
+    shell: &Shell,
+) -> Result<(Workspace, Vec<BuiltTarget>)> {
+    let workspace = load_workspace(cargo_options, app_config, shell)?;
+
+    let profiles_tests =
+        cargo_options.targets.iter().any(|target| matches!(target, Target::Test(_) | Target::Lib));
+    if !profiles_tests {
+        if app_config.each_test {
+            return Err(anyhow!("--each-test can only be used with --test, --lib or --exec"));
+        } else if app_config.test_name.is_some() {
+            return Err(anyhow!("--test-name can only be used with --test, --lib or --exec"));
+        }
+    }
+
+    log::debug!("building profile targets {:?}", cargo_options.targets);
+    let built_targets = build_targets(cargo_options, &workspace)?;
+    Ok((workspace, built_targets))
+}
+
+/// Load the cargo workspace, and settle the options the build needs.
+fn load_workspace(
+    cargo_options: &mut CargoOpts,
+    app_config: &AppConfig,
+    shell: &Shell,
+) -> Result<Workspace> {
+    // Extra rustflags must be set before cargo reads the environment.
+    if !cargo_options.rustflags.is_empty() {
+        add_rustflags(cargo_options);
+    }
+
+    // -Zbuild-std and -Zscript need a nightly toolchain, and -Zbuild-std an
+    // explicit target
+    if let Some(flag) = cargo_options.unstable_flags.first() {
+        let (release, host) = workspace::rustc_version()?;
+        if !release.contains("-nightly") && !release.contains("-dev") {
+            let feature = match flag.as_str() {
+                "script" => "a single-file package",
+                _ => "--build-std",
+            };
+            return Err(anyhow!("{feature} requires a nightly toolchain, found rustc {release}"));
+        }
+        let builds_std = cargo_options.unstable_flags.iter().any(|flag| flag == "build-std");
+        if builds_std && cargo_options.target_triple.is_none() {
+            cargo_options.target_triple = Some(host);
+        }
+    }
+
+    let mut workspace = Workspace::new(cargo_options, app_config.manifest_path.as_deref())?;
+    log::debug!("using cargo workspace at {}", workspace.root().display());
+
+    if cargo_options.separate_target_dir {
+        let target_dir = workspace.target_dir().join("frame-pointers");
+        workspace.set_target_dir(target_dir);
+    }
+
+    enable_required_features(cargo_options, &workspace, shell)?;
+    Ok(workspace)
+}
+
+/// Profile `cargo build` of the targets selected by `cargo_options`, instead
+/// of the targets themselves.
+///
+/// rustc, build scripts and proc-macros run as processes of their own, so all
+/// processes are recorded, as with `--all-processes`, and the PIDs of cargo's
+/// process tree are saved in the trace metadata. The trace is named after the
+/// package and profile, e.g.
+/// `foo-release-build_Time-Profiler_2021-05-09_123456-789.trace`.
+fn profile_build(
+    cargo_options: &mut CargoOpts,
+    xctrace_tool: &instruments::XcodeInstruments,
+    app_config: &AppConfig,
+    shell: &Shell,
+) -> Result<()> {
+    let run = load_workspace(cargo_options, app_config, shell).and_then(|workspace| {
+        let (cargo, build_args) = workspace.build_command(cargo_options);
+        let cargo = find_program(&cargo.to_string_lossy())?;
+        Ok((workspace, cargo, build_args))
+    });
+    let (workspace, cargo, build_args) = match run {
+        Ok(run) => run,
+        Err(e) => {
+            shell.error(&e)?;
+            return Err(e);
+        }
+    };
+    if !app_config.target_args.is_empty() {
+        shell.warn("arguments for the target are ignored with --profile-build")?;
+    }
+
+    let package = build_package_name(&cargo_options.package, &workspace);
+    let name = format!("{}-{}-build", package, cargo_options.profile);
+    let mut metadata = TraceMetadata::new();
+    metadata.insert("profiled-build".into(), package.into());
+    metadata.insert("profile".into(), cargo_options.profile.as_str().into());
+
+    let run = ProfileRun {
+        target_filepath: &cargo,
+        target_args: &build_args,
+        env: Vec::new(),
+        attach_pid: None,
+        name: Some(&name),
+        trace_dir: &workspace.target_dir().join("instruments"),
+        metadata,
+    };
+    let (trace_filepath, target_exit) = match instruments::profile_target(
+        &run,
+        xctrace_tool,
+        app_config,
+        shell,
+        Some(&workspace),
+    ) {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            shell.error(&e)?;
+            return Err(e);
+        }
+    };
+    print_trace_filepath(&trace_filepath, shell, Some(&workspace))?;
+
+    if !app_config.no_open {
+        launch_instruments(&trace_filepath)?;
+    }
+    check_target_errors(app_config, target_failed(target_exit), shell)
+}
+
+/// The name of the package(s) built with `package`, for naming the trace of
+/// their build.
+fn build_package_name(package: &Package, workspace: &Workspace) -> String {
+    match package {
+        Package::Package(name) => name.clone(),
+        Package::Default => match workspace.default_members().as_slice() {
+            [package] => package.name.to_string(),
+            _ => "workspace".to_owned(),
+        },
+        Package::All => "workspace".to_owned(),
+    }
+}
+
+/// Profile a single built target, returning the path of its trace file and
+/// whether it exited with an error, as told by [`record`].
+fn profile_built_target(
+    built_target: &BuiltTarget,
+    trace_dir: &Path,
+    xctrace_tool: &instruments::XcodeInstruments,
+    app_config: &AppConfig,
+    shell: &Shell,
+    workspace: Option<&Workspace>,
+) -> Result<(Option<PathBuf>, bool)> {
+    // 4b. Pick a single benchmark to run, if asked
+    let bench_id = match (&app_config.bench_id, built_target.bench_harness) {
+        (Some(filter), Some(harness)) => {
+            match select_bench_id(&built_target.path, harness, filter.as_deref()) {
+                Ok(bench_id) => Some(bench_id),
+                Err(e) => {
+                    shell.error(&e)?;
+                    return Err(e);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let target_args = match (&built_target.target, built_target.bench_harness) {
+        (Target::Test(_) | Target::Lib, _) => {
+            test_harness_args(app_config.test_name.as_deref(), &app_config.target_args)
+        }
+        (_, Some(harness)) => bench_harness_args(
+            harness,
+            bench_id.as_deref(),
+            app_config.time_limit,
+            &app_config.target_args,
+        ),
+        _ => app_config.target_args.clone(),
+    };
+
+    let mut metadata = built_target.metadata.clone();
+    let (target_filepath, target_args, env) = match (&built_target.target, &app_config.lib_host) {
+        // 4c. Launch the host of a cdylib instead
+        (Target::Cdylib, Some(host)) => {
+            let library = &built_target.path;
+            metadata.insert("profiled-library".into(), library.to_string_lossy().into());
+            metadata.insert("library-host".into(), host.as_str().into());
+            match lib_host_command(host, app_config.lib_env.as_deref(), library, &target_args)
+                .and_then(|(program, args, env)| Ok((find_program(&program)?, args, env)))
+            {
+                Ok(command) => command,
+                Err(e) => {
+                    shell.error(&e)?;
+                    return Err(e);
+                }
+            }
+        }
+        _ => (built_target.path.clone(), target_args, Vec::new()),
+    };
+    // The variables needed by the lib host win over the user's own.
+    let env = {
+        let mut target_env = app_config.env.clone();
+        merge_env(&mut target_env, env);
+        target_env
+    };
+    let name = match built_target.target {
+        Target::Cdylib => Some(built_target.package.as_str()),
+        _ => bench_id.as_deref(),
+    };
+
+    // 4d. Attach to the target if it is already running
+    let attach_pid = match app_config.attach {
+        Some(ref process) => match find_attach_pid(process.as_deref(), &target_filepath) {
+            Ok(pid) => {
+                metadata.insert("attached-pid".into(), pid.into());
+                Some(pid)
+            }
             Err(e) => {
-                workspace.gctx().shell().error(&e)?;
-                return Ok(());
+                shell.error(&e)?;
+                return Err(e);
             }
+        },
+        None => None,
+    };
+
+    let run = ProfileRun {
+        target_filepath: &target_filepath,
+        target_args: &target_args,
+        env,
+        attach_pid,
+        name,
+        trace_dir,
+        metadata,
+    };
+    record(&run, xctrace_tool, app_config, shell, workspace)
+}
+
+/// Record one trace per test in the test harness `built_target`, returning
+/// the name of each test along with the path of its trace file, and whether
+/// any of the tests exited with an error.
+///
+/// A failure to profile one test is reported, and does not stop the remaining
+/// tests from being profiled.
+fn profile_each_test(
+    built_target: &BuiltTarget,
+    trace_dir: &Path,
+    xctrace_tool: &instruments::XcodeInstruments,
+    app_config: &AppConfig,
+    shell: &Shell,
+    workspace: Option<&Workspace>,
+) -> Result<(Traces, bool)> {
+    let target_filepath = &built_target.path;
+    let tests = list_tests(target_filepath, &app_config.target_args)?;
+    if tests.is_empty() {
+        let e = anyhow!("no tests found in {}", target_filepath.display());
+        shell.error(&e)?;
+        return Err(e);
+    }
+
+    let mut traces = Vec::new();
+    let mut any_failed = false;
+    for test in tests {
+        if instruments::was_interrupted() {
+            break;
+        }
+        let target_args = test_harness_args(Some(&test), &app_config.target_args);
+        let run = ProfileRun {
+            target_filepath,
+            target_args: &target_args,
+            env: app_config.env.clone(),
+            attach_pid: None,
+            name: Some(&test),
+            trace_dir,
+            metadata: built_target.metadata.clone(),
         };
+        let (trace_filepath, failed) = record(&run, xctrace_tool, app_config, shell, workspace)?;
+        traces.push((test, trace_filepath));
+        any_failed |= failed;
+    }
+    Ok((traces, any_failed))
+}
 
-    // 5. Print the trace file's relative path
-    {
-        let trace_shortpath = trace_filepath
-            .strip_prefix(workspace.root().as_os_str())
-            .unwrap_or(trace_filepath.as_path())
-            .to_string_lossy();
-        workspace.gctx().shell().status("Trace file", trace_shortpath)?;
+/// List the tests that the test harness at `target_filepath` runs with
+/// `target_args`.
+fn list_tests(target_filepath: &Path, target_args: &[String]) -> Result<Vec<String>> {
+    let (list_args, ignored_args) = test_list_args(target_args);
+    let tests = list_harness_entries(target_filepath, &list_args, &[], "test")?;
+    let Some(ignored_args) = ignored_args else {
+        return Ok(tests);
+    };
+    let ignored = list_harness_entries(target_filepath, &ignored_args, &[], "test")?;
+    Ok(tests.into_iter().filter(|test| !ignored.contains(test)).collect())
+}
+
+/// The arguments to list the tests a test harness runs with `target_args`,
+/// which may filter or skip tests, and unless those ask for `#[ignore]` tests,
+/// the arguments to list the ignored ones among them.
+///
+/// `--list` shows `#[ignore]` tests too, but like `cargo test`, the harness
+/// only runs them with `--ignored` or `--include-ignored`.
+fn test_list_args(target_args: &[String]) -> (Vec<&str>, Option<Vec<&str>>) {
+    let mut list_args = vec!["--list", "--format", "terse"];
+    list_args.extend(target_args.iter().map(String::as_str));
+    if target_args.iter().any(|arg| arg == "--ignored" || arg == "--include-ignored") {
+        return (list_args, None);
     }
+    let ignored_args = [&list_args[..], &["--ignored"]].concat();
+    (list_args, Some(ignored_args))
+}
 
-    // 6. Open Xcode Instruments if asked
-    if !app_config.no_open {
-        launch_instruments(&trace_filepath)?;
+/// Profile `run`, returning the path of its trace file and whether its target
+/// exited with an error, for `--fail-on-target-error`.
+///
+/// If profiling fails, the error is reported and no trace file is returned.
+fn record(
+    run: &ProfileRun,
+    xctrace_tool: &instruments::XcodeInstruments,
+    app_config: &AppConfig,
+    shell: &Shell,
+    workspace: Option<&Workspace>,
+) -> Result<(Option<PathBuf>, bool)> {
+    match instruments::profile_target(run, xctrace_tool, app_config, shell, workspace) {
+        Ok((trace_filepath, target_exit)) => {
+            print_trace_filepath(&trace_filepath, shell, workspace)?;
+            Ok((Some(trace_filepath), target_failed(target_exit)))
+        }
+        Err(e) => {
+            shell.error(&e)?;
+            Ok((None, false))
+        }
     }
+}
+
+/// Whether the target of a recording exited with an error. Stopping it with
+/// Ctrl-C is not a failure of its own.
+fn target_failed(target_exit: Option<instruments::TargetExit>) -> bool {
+    target_exit.is_some_and(|exit| !exit.success()) && !instruments::was_interrupted()
+}
+
+/// The traces recorded in a run, each named after its target or test, or
+/// `None` where profiling failed.
+type Traces = Vec<(String, Option<PathBuf>)>;
 
+/// Print the path of a trace file, relative to the workspace root if there is
+/// one.
+fn print_trace_filepath(
+    trace_filepath: &Path,
+    shell: &Shell,
+    workspace: Option<&Workspace>,
+) -> Result<()> {
+    let trace_shortpath = trace_shortpath(trace_filepath, workspace.map(Workspace::root));
+    shell.status("Trace file", trace_shortpath)?;
     Ok(())
 }
 
+/// Return the path of a trace file relative to `root`, if it is inside it.
+fn trace_shortpath(trace_filepath: &Path, root: Option<&Path>) -> String {
+    root.and_then(|root| trace_filepath.strip_prefix(root).ok())
+        .unwrap_or(trace_filepath)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Render a table of the traces recorded in this run.
+///
+/// The returned string is similar to
+///
+/// ```text
+/// target         trace file
+/// --------------------------
+/// foo (bin)      target/instruments/foo_Time-Profiler_2021-05-09_123456-789.trace
+/// bar (example)  failed
+/// ```
+fn render_trace_summary(traces: &[(String, Option<PathBuf>)], root: Option<&Path>) -> String {
+    let max_width = traces.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max(6);
+    let mut output = format!("{:width$}trace file", "target", width = max_width + 2);
+    output.push_str(&format!("\n{:-<width$}", "", width = max_width + 12));
+    for (name, trace_filepath) in traces {
+        let trace = match trace_filepath {
+            Some(path) => trace_shortpath(path, root),
+            None => "failed".into(),
+        };
+        output.push_str(&format!("\n{:width$}{}", name, trace, width = max_width + 2));
+    }
+    output
+}
+
+/// Ask the harness at `harness_filepath` for the names of its entries of the
+/// given `kind` (e.g. `"test"` or `"benchmark"`), by running it with
+/// `list_args` and the extra environment variables in `env`.
+fn list_harness_entries(
+    harness_filepath: &Path,
+    list_args: &[&str],
+    env: &[(&str, &str)],
+    kind: &str,
+) -> Result<Vec<String>> {
+    let output =
+        Command::new(harness_filepath).args(list_args).envs(env.iter().copied()).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "failed to list tests in {}: {}",
+            harness_filepath.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    Ok(parse_harness_list(&String::from_utf8_lossy(&output.stdout), kind))
+}
+
+/// Find the single benchmark to run in the bench target at `harness_filepath`.
+///
+/// The `filter` must be the exact ID of one of the benchmarks exposed by the
+/// harness. Without a filter, the user is asked to pick one.
+fn select_bench_id(
+    harness_filepath: &Path,
+    harness: BenchHarness,
+    filter: Option<&str>,
+) -> Result<String> {
+    // libtest, Criterion and Divan all list their benchmarks as
+    // `<id>: benchmark` in the terse format. Divan only accepts `--format`
+    // when it believes it runs under cargo-nextest, which it detects through
+    // `NEXTEST=1`.
+    let list_args = ["--bench", "--list", "--format", "terse"];
+    let bench_ids = match harness {
+        BenchHarness::Libtest | BenchHarness::Criterion => {
+            list_harness_entries(harness_filepath, &list_args, &[], "benchmark")?
+        }
+        BenchHarness::Divan => {
+            list_harness_entries(harness_filepath, &list_args, &[("NEXTEST", "1")], "benchmark")?
+        }
+        BenchHarness::Custom => {
+            return match filter {
+                Some(filter) => Ok(filter.to_owned()),
+                None => Err(anyhow!("cannot list the benchmarks of a custom bench harness")),
+            };
+        }
+    };
+
+    match filter {
+        Some(filter) if bench_ids.iter().any(|id| id == filter) => Ok(filter.to_owned()),
+        Some(filter) => {
+            let candidates = bench_ids.iter().filter(|id| id.contains(filter)).collect::<Vec<_>>();
+            if candidates.is_empty() {
+                Err(anyhow!("no benchmark '{filter}', available benchmarks: {bench_ids:?}"))
+            } else {
+                Err(anyhow!("no benchmark '{filter}', did you mean one of {candidates:?}"))
+            }
+        }
+        None if bench_ids.is_empty() => Err(anyhow!("no benchmarks found")),
+        None => {
+            let choice = choose("Select a benchmark", &bench_ids)?;
+            Ok(bench_ids[choice].clone())
+        }
+    }
+}
+
+/// Ask the user to pick one of `items` on the terminal, returning its index.
+///
+/// Fails if stdin is not a terminal, listing the available items instead.
+fn choose(prompt: &str, items: &[String]) -> Result<usize> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(anyhow!("{prompt}: not running interactively, choose one of {items:?}"));
+    }
+
+    let mut stderr = std::io::stderr();
+    for (i, item) in items.iter().enumerate() {
+        writeln!(stderr, "{:>4}) {item}", i + 1)?;
+    }
+    loop {
+        write!(stderr, "{prompt} [1-{}]: ", items.len())?;
+        stderr.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(anyhow!("{prompt}: no selection made"));
+        }
+        let line = line.trim();
+        if let Ok(n) = line.parse::<usize>() {
+            if (1..=items.len()).contains(&n) {
+                return Ok(n - 1);
+            }
+        } else if let Some(i) = items.iter().position(|item| item == line) {
+            return Ok(i);
+        }
+        writeln!(stderr, "invalid selection '{line}'")?;
+    }
+}
+
+type EnvVars = Vec<(String, String)>;
+
+/// The program, arguments and environment to profile the cdylib at `library`,
+/// loaded by the `--lib-host` command `host`.
+///
+/// The library is pointed at by the `lib_env` variable, or by default inserted
+/// in the host with `DYLD_INSERT_LIBRARIES`, and found first by `dlopen` with
+/// `DYLD_LIBRARY_PATH`.
+fn lib_host_command(
+    host: &str,
+    lib_env: Option<&str>,
+    library: &Path,
+    target_args: &[String],
+) -> Result<(String, Vec<String>, EnvVars)> {
+    let mut words = shlex::split(host).unwrap_or_default().into_iter();
+    let program = words.next().ok_or_else(|| anyhow!("invalid --lib-host command {:?}", host))?;
+    let args = words.chain(target_args.iter().cloned()).collect();
+
+    let library_path = library.to_string_lossy().into_owned();
+    let env = match lib_env {
+        Some(var) => vec![(var.to_owned(), library_path)],
+        None => {
+            let library_dir = library.parent().unwrap_or(Path::new("")).to_string_lossy();
+            let library_dirs = match std::env::var("DYLD_LIBRARY_PATH") {
+                Ok(dirs) if !dirs.is_empty() => format!("{library_dir}:{dirs}"),
+                _ => library_dir.into_owned(),
+            };
+            vec![
+                ("DYLD_LIBRARY_PATH".to_owned(), library_dirs),
+                ("DYLD_INSERT_LIBRARIES".to_owned(), library_path),
+            ]
+        }
+    };
+    Ok((program, args, env))
+}
+
+/// Find `program` in `PATH` like a shell would, unless it is already a path.
+fn find_program(program: &str) -> Result<PathBuf> {
+    if program.contains('/') {
+        return Ok(PathBuf::from(program));
+    }
+    std::env::var_os("PATH")
+        .and_then(|paths| {
+            std::env::split_paths(&paths).map(|dir| dir.join(program)).find(|path| path.is_file())
+        })
+        .ok_or_else(|| anyhow!("{program} not found in PATH"))
+}
+
+/// Read the `KEY=VALUE` environment variables in each of `env_files`, later
+/// entries taking precedence.
+fn read_env_files(env_files: &[PathBuf]) -> Result<EnvVars> {
+    let mut env = Vec::new();
+    for path in env_files {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read {}: {}", path.display(), e))?;
+        let vars =
+            parse_env_file(&contents).map_err(|e| anyhow!("invalid {}: {}", path.display(), e))?;
+        merge_env(&mut env, vars);
+    }
+    Ok(env)
+}
+
+/// Parse an env file, which has one `KEY=VALUE` entry per line, optionally
+/// preceded by `export` or with a quoted value, blank lines and `#` comments.
+fn parse_env_file(contents: &str) -> Result<EnvVars> {
+    let mut env = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) =
+            opt::parse_env_var(line).map_err(|e| anyhow!("{} on line {}", e, number + 1))?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        env.push((key.trim().to_owned(), value.to_owned()));
+    }
+    Ok(env)
+}
+
+/// Add `vars` to `env`, replacing any variable that is already set.
+fn merge_env(env: &mut EnvVars, vars: impl IntoIterator<Item = (String, String)>) {
+    for (name, value) in vars {
+        match env.iter_mut().find(|(existing, _)| *existing == name) {
+            Some(var) => var.1 = value,
+            None => env.push((name, value)),
+        }
+    }
+}
+
+/// Find the running process to attach to. `process` is a PID or the name of
+/// the process; without it, the process running `target_filepath` is looked
+/// for.
+fn find_attach_pid(process: Option<&str>, target_filepath: &Path) -> Result<u32> {
+    if let Some(pid) = process.and_then(|process| process.parse().ok()) {
+        return Ok(pid);
+    }
+    // On macOS, `comm` is the full path of the executable.
+    let output = Command::new("ps").args(["-axo", "pid=,comm="]).output()?;
+    if !output.status.success() {
+        return Err(anyhow!("failed to list the running processes"));
+    }
+    let target_filepath =
+        std::fs::canonicalize(target_filepath).unwrap_or_else(|_| target_filepath.to_owned());
+    let processes = String::from_utf8_lossy(&output.stdout);
+    let pids = matching_pids(&processes, process, &target_filepath);
+    let description = match process {
+        Some(name) => format!("named {name}"),
+        None => format!("running {}", target_filepath.display()),
+    };
+    match pids.as_slice() {
+        [pid] => Ok(*pid),
+        [] => Err(anyhow!("no process {description} was found")),
+        pids => {
+            let pids = pids.iter().map(u32::to_string).collect::<Vec<_>>();
+            Err(anyhow!(
+                "several processes {description} were found ({}), pass a PID to --attach",
+                pids.join(", ")
+            ))
+        }
+    }
+}
+
+/// Parse the output of `ps -o pid=,comm=`, returning the PIDs of the processes
+/// named `name`, or running `target_filepath`.
+fn matching_pids(processes: &str, name: Option<&str>, target_filepath: &Path) -> Vec<u32> {
+    processes
+        .lines()
+        .filter_map(|line| {
+            let (pid, command) = line.trim_start().split_once(' ')?;
+            let command = Path::new(command.trim());
+            let matches = match name {
+                Some(name) => {
+                    command == Path::new(name) || command.file_name() == Some(name.as_ref())
+                }
+                None => command == target_filepath,
+            };
+            matches.then(|| pid.parse().ok()).flatten()
+        })
+        .collect()
+}
+
+/// Parse the output of `--list --format terse`, which has one `name: kind`
+/// entry per line.
+fn parse_harness_list(list: &str, kind: &str) -> Vec<String> {
+    list.lines()
+        .filter_map(|line| line.trim().rsplit_once(": "))
+        .filter(|(_, entry_kind)| *entry_kind == kind)
+        .map(|(name, _)| name.to_owned())
+        .collect()
+}
+
+/// Whether a binary built for `target_triple` (or for the host, if `None`) is
+/// an arm64 binary, which needs to be resigned before it can be profiled.
+///
+/// x86_64 binaries run under Rosetta don't need this.
+fn needs_codesign(target_triple: Option<&str>) -> bool {
+    match target_triple {
+        Some(triple) => triple.starts_with("aarch64-") || triple.starts_with("arm64"),
+        None => cfg!(target_arch = "aarch64"),
+    }
+}
+
 /// On M1 we need to resign with the specified entitlement.
 ///
 /// See https://github.com/cmyr/cargo-instruments/issues/40#issuecomment-894287229
 /// for more information.
-#[cfg(target_arch = "aarch64")]
-fn codesign(path: &Path, workspace: &Workspace) -> Result<()> {
+fn codesign(path: &Path, shell: &Shell) -> Result<()> {
     use std::fmt::Write;
 
     static ENTITLEMENTS_FILENAME: &str = "entitlements.plist";
@@ -131,79 +882,545 @@ fn codesign(path: &Path, workspace: &Workspace) -> Result<()> {
             write!(&mut msg, "stderr: \"{}\"", String::from_utf8_lossy(&output.stderr))?;
         }
 
-        workspace.gctx().shell().error("Code signing failed")?;
+        shell.error("Code signing failed")?;
     }
     Ok(())
 }
 
-/// Attempts to validate and build the specified target. On success, returns
-/// the path to the built executable.
-fn build_target(cargo_options: &CargoOpts, workspace: &Workspace) -> Result<PathBuf> {
-    use cargo::core::shell::Verbosity;
-    workspace.gctx().shell().set_verbosity(Verbosity::Normal);
+/// An executable produced by `build_targets`.
+struct BuiltTarget {
+    path: PathBuf,
+    /// The target this executable was built from.
+    target: Target,
+    /// The name of the package the target belongs to.
+    package: String,
+    /// The harness the target was built with, if it is a benchmark.
+    bench_harness: Option<BenchHarness>,
+    /// Information about the build to save with the trace.
+    metadata: TraceMetadata,
+}
 
-    let compile_options = make_compile_opts(cargo_options, workspace.gctx())?;
-    let result = cargo::ops::compile(workspace, &compile_options)?;
+impl BuiltTarget {
+    fn new(artifact: &Artifact, workspace: &Workspace) -> Result<BuiltTarget> {
+        let unit_target = &artifact.target;
+        let name = unit_target.name.clone();
+        let pkg = workspace.package(&artifact.package_id)?;
+        let path = match artifact.executable {
+            Some(ref path) => path.clone(),
+            None if unit_target.is_cdylib() => artifact
+                .filenames
+                .iter()
+                .find(|path| matches!(path.extension(), Some("dylib" | "so" | "dll")))
+                .ok_or_else(|| anyhow!("no dynamic library found for {}", name))?
+                .clone(),
+            None => return Err(anyhow!("target {} is not executable", name)),
+        }
+        .into_std_path_buf();
+        // Libraries have one kind per crate type, so they are the fallback.
+        let (target, bench_harness) = if unit_target.is_bench() {
+            (Target::Bench(name), Some(BenchHarness::detect(pkg, &unit_target.name)))
+        } else if unit_target.is_test() {
+            (Target::Test(name), None)
+        } else if unit_target.is_example() {
+            (Target::Example(name), None)
+        } else if unit_target.is_bin() {
+            (Target::Bin(name), None)
+        } else if artifact.executable.is_none() {
+            (Target::Cdylib, None)
+        } else {
+            (Target::Lib, None)
+        };
+        Ok(BuiltTarget {
+            path,
+            target,
+            package: pkg.name.to_string(),
+            bench_harness,
+            metadata: TraceMetadata::new(),
+        })
+    }
 
-    if let Target::Bench(ref bench) = cargo_options.target {
-        result
-            .tests
-            .iter()
-            .find(|unit_output| unit_output.unit.target.name() == bench)
-            .map(|unit_output| unit_output.path.clone())
-            .ok_or_else(|| anyhow!("no benchmark '{}'", bench))
+    /// The executable at `path`, given with `--exec` instead of being built.
+    ///
+    /// It is profiled as a test harness if a test was selected.
+    fn prebuilt(path: &Path, app_config: &AppConfig) -> Result<BuiltTarget> {
+        if !path.is_file() {
+            return Err(anyhow!("no executable found at {}", path.display()));
+        }
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("invalid executable path {:?}", path))?
+            .to_owned();
+        let target = match app_config.each_test || app_config.test_name.is_some() {
+            true => Target::Test(name),
+            false => Target::Bin(name),
+        };
+        let mut metadata = TraceMetadata::new();
+        metadata.insert("prebuilt".into(), true.into());
+        Ok(BuiltTarget {
+            path: std::env::current_dir()?.join(path),
+            target,
+            package: String::new(),
+            bench_harness: None,
+            metadata,
+        })
+    }
+}
+
+/// The harness used to run a benchmark target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BenchHarness {
+    /// The built-in libtest harness, for `#[bench]` functions.
+    Libtest,
+    Criterion,
+    Divan,
+    /// Some other `harness = false` benchmark.
+    Custom,
+}
+
+impl BenchHarness {
+    /// Determine the harness of the bench target `name`, from its manifest and
+    /// the dependencies of its package.
+    fn detect(pkg: &cargo_metadata::Package, name: &str) -> BenchHarness {
+        // `cargo metadata` doesn't tell, so we read the manifest ourselves.
+        let manifest = std::fs::read_to_string(&pkg.manifest_path).unwrap_or_default();
+        if bench_uses_harness(&manifest, name) {
+            return BenchHarness::Libtest;
+        }
+        let depends_on = |name: &str| pkg.dependencies.iter().any(|dep| dep.name == name);
+        if depends_on("criterion") || depends_on("codspeed-criterion-compat") {
+            BenchHarness::Criterion
+        } else if depends_on("divan") || depends_on("codspeed-divan-compat") {
+            BenchHarness::Divan
+        } else {
+            BenchHarness::Custom
+        }
+    }
+}
+
+/// Whether the bench target `name` uses the libtest harness, according to the
+/// package manifest.
+///
+/// This is the default, unless its `[[bench]]` entry sets `harness = false`.
+fn bench_uses_harness(manifest: &str, name: &str) -> bool {
+    let manifest = match manifest.parse::<toml::Table>() {
+        Ok(manifest) => manifest,
+        Err(_) => return true,
+    };
+    manifest
+        .get("bench")
+        .and_then(|benches| benches.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|bench| bench.as_table())
+        .find(|bench| bench.get("name").and_then(|n| n.as_str()) == Some(name))
+        .and_then(|bench| bench.get("harness"))
+        .and_then(|harness| harness.as_bool())
+        .unwrap_or(true)
+}
+
+/// Whether `artifact` is one of the selected `targets`, rather than something
+/// built along with them.
+///
+/// e.g. `--benches` also builds the lib and bins of a package as bench
+/// harnesses, since they have `bench = true` by default; these would
+/// otherwise pass for the lib's unit tests and the bins themselves. Building
+/// an integration test or bench also builds the package's bins, for the
+/// harness to run.
+fn is_selected(artifact: &Artifact, targets: &[Target]) -> bool {
+    let unit_target = &artifact.target;
+    if unit_target.is_bench() || unit_target.is_test() {
+        return true;
+    }
+    if artifact.profile.test {
+        // Only the lib's unit test harness is ever asked for, with `--lib`.
+        return targets.contains(&Target::Lib)
+            && !unit_target.is_bin()
+            && !unit_target.is_example();
+    }
+    let name = unit_target.name.as_str();
+    if unit_target.is_bin() {
+        targets.iter().any(|target| match target {
+            Target::Bin(bin) => bin == name,
+            Target::AllBins | Target::Default => true,
+            _ => false,
+        })
+    } else if unit_target.is_example() {
+        targets.iter().any(|target| match target {
+            Target::Example(example) => example == name,
+            Target::AllExamples => true,
+            _ => false,
+        })
     } else {
-        match result.binaries.as_slice() {
-            [unit_output] => Ok(unit_output.path.clone()),
-            [] => Err(anyhow!("no targets found")),
-            other => Err(anyhow!(
-                "found multiple targets: {:?}",
-                other
-                    .iter()
-                    .map(|unit_output| unit_output.unit.target.name())
-                    .collect::<Vec<&str>>()
-            )),
+        true
+    }
+}
+
+/// Attempts to validate and build the specified targets. On success, returns
+/// the built executables.
+fn build_targets(cargo_options: &CargoOpts, workspace: &Workspace) -> Result<Vec<BuiltTarget>> {
+    // Keep the executables of the selected targets, and libraries only when
+    // they are to be profiled in a host.
+    let wants_cdylib = cargo_options.targets.contains(&Target::Cdylib);
+    let built_targets = workspace
+        .build(cargo_options)?
+        .iter()
+        .filter(|artifact| artifact.executable.is_some() || wants_cdylib)
+        .filter(|artifact| is_selected(artifact, &cargo_options.targets))
+        .map(|artifact| BuiltTarget::new(artifact, workspace))
+        .collect::<Result<Vec<_>>>()?;
+
+    if cargo_options.targets == [Target::Default] {
+        return match built_targets.len() {
+            1 => Ok(built_targets),
+            0 => Err(anyhow!("no targets found")),
+            _ => select_default_binary(built_targets, workspace)
+                .map(|built_target| vec![built_target]),
+        };
+    }
+
+    // Check that every target named explicitly was found.
+    for target in &cargo_options.targets {
+        let found = match target {
+            Target::AllBins | Target::AllExamples | Target::AllBenches => true,
+            named => built_targets.iter().any(|built| built.target == *named),
+        };
+        if !found {
+            return Err(match target {
+                Target::Bench(bench) => anyhow!("no benchmark '{}'", bench),
+                Target::Test(test) => anyhow!("no test target '{}'", test),
+                Target::Lib => anyhow!("no library unit tests found"),
+                Target::Cdylib => {
+                    anyhow!("no cdylib found, `crate-type` must include \"cdylib\" in `[lib]`")
+                }
+                other => anyhow!("no target {}", other),
+            });
         }
     }
+
+    if built_targets.is_empty() {
+        return Err(anyhow!("no targets found"));
+    }
+    Ok(built_targets)
+}
+
+/// Pick the binary to profile when no target was specified and several were
+/// built.
+///
+/// Like `cargo run`, this respects the `default-run` key of the manifest.
+/// Failing that, the user is asked to choose one when running interactively.
+fn select_default_binary(
+    mut built_targets: Vec<BuiltTarget>,
+    workspace: &Workspace,
+) -> Result<BuiltTarget> {
+    use std::io::IsTerminal;
+
+    let names = built_targets
+        .iter()
+        .map(|built_target| match built_target.target {
+            Target::Bin(ref name) => name.clone(),
+            ref other => other.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    let members = workspace.members();
+    let is_default_run = |built_target: &BuiltTarget, name: &String| {
+        members
+            .iter()
+            .any(|pkg| pkg.name == built_target.package && pkg.default_run.as_ref() == Some(name))
+    };
+    let default_runs = (0..built_targets.len())
+        .filter(|&i| is_default_run(&built_targets[i], &names[i]))
+        .collect::<Vec<_>>();
+
+    let (choice, selected_by) = match default_runs.as_slice() {
+        [i] => (*i, "default-run"),
+        _ if std::io::stdin().is_terminal() => {
+            (choose("Select a binary to profile", &names)?, "user")
+        }
+        _ => return Err(anyhow!("found multiple targets: {:?}", names)),
+    };
+
+    let mut built_target = built_targets.swap_remove(choice);
+    built_target.metadata.insert("selected-target".into(), names[choice].clone().into());
+    built_target.metadata.insert("selected-by".into(), selected_by.into());
+    built_target.metadata.insert("candidate-targets".into(), names.into());
+    Ok(built_target)
+}
+
+/// Arguments for a benchmark harness.
+///
+/// Like `cargo bench`, this passes `--bench` so that the harness runs in
+/// benchmark mode rather than test mode. Criterion is additionally put in
+/// profiling mode for the duration of the time limit, so that the recording
+/// is spent in the measured loop instead of in warm-up and analysis. If a
+/// benchmark ID is given, only that benchmark is run.
+fn bench_harness_args(
+    harness: BenchHarness,
+    bench_id: Option<&str>,
+    time_limit: Option<usize>,
+    target_args: &[String],
+) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(id) = bench_id {
+        args.push(id.to_owned());
+        args.push("--exact".to_owned());
+    }
+    if !target_args.iter().any(|arg| arg == "--bench") {
+        args.push("--bench".to_owned());
+    }
+    if let (BenchHarness::Criterion, Some(limit_millis)) = (harness, time_limit) {
+        if !target_args.iter().any(|arg| arg == "--profile-time") {
+            args.push("--profile-time".to_owned());
+            args.push((limit_millis as f64 / 1000.0).to_string());
+        }
+    }
+    args.extend(target_args.iter().cloned());
+    args
+}
+
+/// Arguments for a libtest harness.
+///
+/// Tests are run on a single thread with their output shown, so that the
+/// trace only contains the work done by the test itself. If a test name is
+/// given, only that test is run.
+fn test_harness_args(test_name: Option<&str>, target_args: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(name) = test_name {
+        args.push(name.to_owned());
+        args.push("--exact".to_owned());
+    }
+    args.push("--nocapture".to_owned());
+    args.push("--test-threads=1".to_owned());
+    args.extend(target_args.iter().cloned());
+    args
+}
+
+/// Add `cargo_options.rustflags` to the rustflags of the build, without
+/// replacing the ones the user has set.
+///
+/// Cargo only uses the first rustflags it finds, in order: the
+/// `CARGO_ENCODED_RUSTFLAGS` and `RUSTFLAGS` environment variables, then the
+/// `target.<triple>.rustflags` and `target.<cfg>.rustflags` config matching
+/// the target, and last `build.rustflags`. If neither variable is set, the
+/// flags are added to every `target` entry that sets rustflags, whether in
+/// the config files, in `--config` or in a `CARGO_TARGET_<TRIPLE>_RUSTFLAGS`
+/// variable, and to `build.rustflags`. Config arrays are merged, so each of
+/// them keeps the user's own flags, and whichever one cargo picks has ours.
+fn add_rustflags(cargo_options: &mut CargoOpts) {
+    let env_vars = (std::env::var("CARGO_ENCODED_RUSTFLAGS"), std::env::var("RUSTFLAGS"));
+    if let Some((var, value)) =
+        extend_env_rustflags(env_vars.0.ok(), env_vars.1.ok(), &cargo_options.rustflags)
+    {
+        std::env::set_var(var, value);
+        return;
+    }
+
+    for (var, value) in extend_target_env_rustflags(std::env::vars(), &cargo_options.rustflags) {
+        std::env::set_var(var, value);
+    }
+    let mut keys = BTreeSet::new();
+    for config in cargo_config_tables(&cargo_options.config) {
+        keys.extend(target_rustflags_keys(&config));
+    }
+    let flags = &cargo_options.rustflags;
+    for key in keys {
+        cargo_options.config.push(format!("target.{key:?}.rustflags={flags:?}"));
+    }
+    cargo_options.config.push(format!("build.rustflags={flags:?}"));
+}
+
+/// Add `extra` to the rustflags set in the environment, returning the variable
+/// to set and its new value, if one of them is set.
+fn extend_env_rustflags(
+    encoded_rustflags: Option<String>,
+    rustflags: Option<String>,
+    extra: &[String],
+) -> Option<(&'static str, String)> {
+    match (encoded_rustflags, rustflags) {
+        (Some(encoded), _) => {
+            let flags = encoded.split('\x1f').filter(|flag| !flag.is_empty());
+            let flags = flags.chain(extra.iter().map(String::as_str)).collect::<Vec<_>>();
+            Some(("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f")))
+        }
+        (None, Some(rustflags)) => {
+            let flags = rustflags.split_whitespace();
+            let flags = flags.chain(extra.iter().map(String::as_str)).collect::<Vec<_>>();
+            Some(("RUSTFLAGS", flags.join(" ")))
+        }
+        (None, None) => None,
+    }
+}
+
+/// Add `extra` to every `CARGO_TARGET_<TRIPLE>_RUSTFLAGS` variable in `vars`,
+/// returning the variables to set and their new values.
+fn extend_target_env_rustflags(
+    vars: impl Iterator<Item = (String, String)>,
+    extra: &[String],
+) -> Vec<(String, String)> {
+    vars.filter(|(var, _)| var.starts_with("CARGO_TARGET_") && var.ends_with("_RUSTFLAGS"))
+        .map(|(var, value)| {
+            let flags = value.split_whitespace().chain(extra.iter().map(String::as_str));
+            (var, flags.collect::<Vec<_>>().join(" "))
+        })
+        .collect()
 }
 
-/// Generate `CompileOptions` for Cargo.
+/// Read the cargo config that applies to a build started from the current
+/// directory: the `.cargo/config.toml` files of the directory and its
+/// parents, the one in the cargo home, and the `--config` values in
+/// `overrides` that are TOML rather than paths.
 ///
-/// This additionally filters options based on user args, so that Cargo
-/// builds as little as possible.
-fn make_compile_opts(cargo_options: &CargoOpts, cfg: &GlobalContext) -> Result<CompileOptions> {
-    use cargo::core::compiler::UserIntent;
-    use cargo::ops::CompileFilter;
+/// Files that can't be read or parsed are skipped; cargo reports them itself.
+fn cargo_config_tables(overrides: &[String]) -> Vec<toml::Table> {
+    let mut dirs = Vec::new();
+    if let Ok(cwd) = std::env::current_dir() {
+        dirs.extend(cwd.ancestors().map(|dir| dir.join(".cargo")));
+    }
+    match std::env::var_os("CARGO_HOME") {
+        Some(cargo_home) => dirs.push(PathBuf::from(cargo_home)),
+        None => dirs.extend(std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo"))),
+    }
+
+    let files = dirs.iter().flat_map(|dir| [dir.join("config.toml"), dir.join("config")]);
+    let files = files.filter_map(|path| std::fs::read_to_string(path).ok());
+    let tables = files.chain(overrides.iter().cloned());
+    tables.filter_map(|config| config.parse::<toml::Table>().ok()).collect()
+}
+
+/// The keys of the `target` entries of `config` that set rustflags, e.g.
+/// `x86_64-apple-darwin` or `cfg(target_os = "macos")`.
+fn target_rustflags_keys(config: &toml::Table) -> Vec<String> {
+    let Some(targets) = config.get("target").and_then(toml::Value::as_table) else {
+        return Vec::new();
+    };
+    let sets_rustflags = |(_, target): &(&String, &toml::Value)| {
+        target.as_table().is_some_and(|target| target.contains_key("rustflags"))
+    };
+    targets.iter().filter(sets_rustflags).map(|(key, _)| key.clone()).collect()
+}
 
-    let mut compile_options = CompileOptions::new(cfg, UserIntent::Build)?;
-    let profile = &cargo_options.profile;
+/// Enable the `required-features` of the examples and benches asked for by
+/// name.
+///
+/// Cargo would otherwise skip building them, and we would find no target to
+/// profile. Features that the package doesn't define are reported as an error.
+fn enable_required_features(
+    cargo_options: &mut CargoOpts,
+    workspace: &Workspace,
+    shell: &Shell,
+) -> Result<()> {
+    if cargo_options.features.all_features {
+        return Ok(());
+    }
 
-    compile_options.build_config.requested_profile = InternedString::new(profile);
-    compile_options.cli_features = cargo_options.features.clone();
-    compile_options.spec = cargo_options.package.clone().into();
+    let mut features = cargo_options.features.features.clone();
+    for target in &cargo_options.targets {
+        let (name, is_kind): (&str, fn(&cargo_metadata::Target) -> bool) = match target {
+            Target::Example(name) => (name, cargo_metadata::Target::is_example),
+            Target::Bench(name) => (name, cargo_metadata::Target::is_bench),
+            _ => continue,
+        };
 
-    if cargo_options.target != Target::Default {
-        let (bins, examples, benches) = match &cargo_options.target {
-            Target::Bin(bin) => (vec![bin.clone()], vec![], vec![]),
-            Target::Example(bin) => (vec![], vec![bin.clone()], vec![]),
-            Target::Bench(bin) => (vec![], vec![], vec![bin.clone()]),
-            _ => unreachable!(),
+        let packages: Vec<_> = match cargo_options.package {
+            Package::Package(ref package) => {
+                workspace.members().into_iter().filter(|pkg| pkg.name == package).collect()
+            }
+            Package::All => workspace.members(),
+            Package::Default => workspace.default_members(),
         };
 
-        compile_options.filter = CompileFilter::from_raw_arguments(
-            false,
-            bins,
-            false,
-            Vec::new(),
-            false,
-            examples,
-            false,
-            benches,
-            false,
-            false,
-        );
+        for pkg in packages {
+            let required = match pkg
+                .targets
+                .iter()
+                .find(|unit_target| is_kind(unit_target) && unit_target.name == name)
+            {
+                Some(unit_target) => &unit_target.required_features,
+                None => continue,
+            };
+
+            let feature_map = &pkg.features;
+            let enabled = enabled_features(&pkg.name, feature_map, &cargo_options.features);
+            let missing = required
+                .iter()
+                .filter(|feature| !enabled.contains(feature.as_str()))
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                continue;
+            }
+
+            let undefined = missing
+                .iter()
+                .filter(|feature| {
+                    !feature.contains('/') && !feature_map.contains_key(feature.as_str())
+                })
+                .collect::<Vec<_>>();
+            if !undefined.is_empty() {
+                return Err(anyhow!(
+                    "{} requires the features {:?}, which are not defined by package `{}`",
+                    target,
+                    undefined,
+                    pkg.name
+                ));
+            }
+
+            let missing_list =
+                missing.iter().map(|feature| format!("`{feature}`")).collect::<Vec<_>>();
+            shell.status(
+                "Enabling",
+                format!("features {} required by {}", missing_list.join(", "), target),
+            )?;
+            for feature in missing {
+                // Qualify the feature with its package, so that it is enabled
+                // on the right member of the workspace.
+                let feature = match feature.contains('/') {
+                    true => feature.to_owned(),
+                    false => format!("{}/{}", pkg.name, feature),
+                };
+                features.insert(feature);
+            }
+        }
     }
-    Ok(compile_options)
+
+    cargo_options.features.features = features;
+    Ok(())
+}
+
+/// Return the features of package `pkg_name` that are enabled by `cli_features`,
+/// including the features they enable in turn.
+fn enabled_features<'a>(
+    pkg_name: &str,
+    feature_map: &'a BTreeMap<String, Vec<String>>,
+    cli_features: &'a CliFeatures,
+) -> BTreeSet<&'a str> {
+    let mut pending = Vec::new();
+    for feature in &cli_features.features {
+        match feature.split_once('/') {
+            None => pending.push(feature.as_str()),
+            Some((dep_name, dep_feature)) if dep_name.trim_end_matches('?') == pkg_name => {
+                pending.push(dep_feature)
+            }
+            _ => (),
+        }
+    }
+    if cli_features.uses_default_features {
+        pending.push("default");
+    }
+
+    let mut enabled = BTreeSet::new();
+    while let Some(name) = pending.pop() {
+        if !enabled.insert(name) {
+            continue;
+        }
+        // Other values are `dep:name` or features of dependencies.
+        for feature in feature_map.get(name).into_iter().flatten() {
+            if !feature.contains([':', '/']) {
+                pending.push(feature.as_str());
+            }
+        }
+    }
+    enabled
 }
 
 /// Launch Xcode Instruments on the provided trace file.
@@ -215,3 +1432,288 @@ fn launch_instruments(trace_filepath: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn harness_list() {
+        let list = "parser::tests::small: test\nparser::tests::big_input: test\nsum: benchmark\n";
+        let criterion_list = "parse/small: benchmark\nparse/large: benchmark\n";
+        let divan_list = "parse::small: benchmark\nsort::vec::100: benchmark\n";
+        assert_eq!(
+            parse_harness_list(list, "test"),
+            vec!["parser::tests::small", "parser::tests::big_input"]
+        );
+        assert_eq!(parse_harness_list(list, "benchmark"), vec!["sum"]);
+        assert!(parse_harness_list("", "test").is_empty());
+        assert_eq!(
+            parse_harness_list(criterion_list, "benchmark"),
+            vec!["parse/small", "parse/large"]
+        );
+        assert_eq!(
+            parse_harness_list(divan_list, "benchmark"),
+            vec!["parse::small", "sort::vec::100"]
+        );
+    }
+
+    #[test]
+    fn ignored_tests_are_skipped() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let target_args = args(&["parser", "--skip", "slow"]);
+        let (list_args, ignored_args) = test_list_args(&target_args);
+        assert_eq!(list_args, ["--list", "--format", "terse", "parser", "--skip", "slow"]);
+        assert_eq!(
+            ignored_args.unwrap(),
+            ["--list", "--format", "terse", "parser", "--skip", "slow", "--ignored"]
+        );
+
+        for asked in ["--ignored", "--include-ignored"] {
+            let target_args = args(&[asked]);
+            let (list_args, ignored_args) = test_list_args(&target_args);
+            assert_eq!(list_args, ["--list", "--format", "terse", asked]);
+            assert!(ignored_args.is_none());
+        }
+    }
+
+    #[test]
+    fn harness_args() {
+        let extra = vec!["--include-ignored".to_owned()];
+        assert_eq!(
+            test_harness_args(Some("a::b"), &extra),
+            vec!["a::b", "--exact", "--nocapture", "--test-threads=1", "--include-ignored"]
+        );
+        assert_eq!(test_harness_args(None, &[]), vec!["--nocapture", "--test-threads=1"]);
+    }
+
+    #[test]
+    fn rustflags_are_extended() {
+        let extra = vec!["-Cforce-frame-pointers=yes".to_owned()];
+        assert_eq!(extend_env_rustflags(None, None, &extra), None);
+        assert_eq!(
+            extend_env_rustflags(None, Some("-C target-cpu=native".into()), &extra),
+            Some(("RUSTFLAGS", "-C target-cpu=native -Cforce-frame-pointers=yes".into()))
+        );
+        assert_eq!(
+            extend_env_rustflags(Some("--cfg\x1ffoo".into()), Some("ignored".into()), &extra),
+            Some(("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1ffoo\x1f-Cforce-frame-pointers=yes".into()))
+        );
+        assert_eq!(
+            extend_env_rustflags(Some(String::new()), None, &extra),
+            Some(("CARGO_ENCODED_RUSTFLAGS", "-Cforce-frame-pointers=yes".into()))
+        );
+    }
+
+    #[test]
+    fn target_rustflags_are_extended() {
+        let extra = vec!["-Cforce-frame-pointers=yes".to_owned()];
+        let vars = [
+            ("CARGO_TARGET_AARCH64_APPLE_DARWIN_RUSTFLAGS", "-C target-cpu=native"),
+            ("CARGO_TARGET_AARCH64_APPLE_DARWIN_RUNNER", "sudo"),
+            ("RUSTFLAGS_UNRELATED", "-O"),
+        ];
+        let vars = vars.iter().map(|(var, value)| (var.to_string(), value.to_string()));
+        assert_eq!(
+            extend_target_env_rustflags(vars, &extra),
+            vec![(
+                "CARGO_TARGET_AARCH64_APPLE_DARWIN_RUSTFLAGS".to_owned(),
+                "-C target-cpu=native -Cforce-frame-pointers=yes".to_owned()
+            )]
+        );
+
+        let config = r#"
+            build.rustflags = ["-Dwarnings"]
+            target.aarch64-apple-darwin.linker = "clang"
+            target.x86_64-apple-darwin.rustflags = ["-Ctarget-cpu=native"]
+            target.'cfg(target_os = "macos")'.rustflags = ["--cfg", "mac"]
+        "#;
+        assert_eq!(
+            target_rustflags_keys(&config.parse().unwrap()),
+            vec!["cfg(target_os = \"macos\")", "x86_64-apple-darwin"]
+        );
+        assert!(target_rustflags_keys(&"build.jobs = 4".parse().unwrap()).is_empty());
+
+        let overrides = ["target.x86_64-apple-darwin.rustflags=['-Copt-level=1']".to_owned()];
+        let tables = cargo_config_tables(&overrides);
+        assert!(tables
+            .iter()
+            .any(|config| target_rustflags_keys(config) == ["x86_64-apple-darwin"]));
+    }
+
+    #[test]
+    fn features_are_expanded() {
+        let mut feature_map = BTreeMap::new();
+        feature_map.insert("default".to_owned(), vec!["std".to_owned()]);
+        feature_map.insert("std".to_owned(), vec![]);
+        feature_map.insert("extra".to_owned(), vec!["std".to_owned(), "dep:extra".to_owned()]);
+        feature_map.insert("svg".to_owned(), vec!["resvg/text".to_owned()]);
+
+        let cli_features = CliFeatures::from_command_line(&["extra".to_owned()], false, false);
+        let enabled = enabled_features("foo", &feature_map, &cli_features);
+        assert_eq!(enabled.into_iter().collect::<Vec<_>>(), vec!["extra", "std"]);
+
+        let cli_features = CliFeatures::from_command_line(&["foo/svg".to_owned()], false, true);
+        let enabled = enabled_features("foo", &feature_map, &cli_features);
+        assert_eq!(enabled.into_iter().collect::<Vec<_>>(), vec!["default", "std", "svg"]);
+    }
+
+    #[test]
+    fn bench_harness_from_manifest() {
+        let manifest = r#"
+            [package]
+            name = "foo"
+
+            [[bench]]
+            name = "parse"
+            harness = false
+
+            [[bench]]
+            name = "sum"
+        "#;
+        assert!(!bench_uses_harness(manifest, "parse"));
+        assert!(bench_uses_harness(manifest, "sum"));
+        assert!(bench_uses_harness(manifest, "auto_discovered"));
+    }
+
+    #[test]
+    fn lib_host_env() {
+        let library = Path::new("/ws/target/release/libfoo.dylib");
+        let (program, args, env) =
+            lib_host_command("python3 'my bench.py'", Some("FOO_LIB"), library, &["-v".into()])
+                .unwrap();
+        assert_eq!(program, "python3");
+        assert_eq!(args, vec!["my bench.py", "-v"]);
+        assert_eq!(env, vec![("FOO_LIB".to_owned(), "/ws/target/release/libfoo.dylib".to_owned())]);
+
+        let (_, _, env) = lib_host_command("node", None, library, &[]).unwrap();
+        assert_eq!(
+            env[1],
+            ("DYLD_INSERT_LIBRARIES".into(), "/ws/target/release/libfoo.dylib".into())
+        );
+        assert!(env[0].1.starts_with("/ws/target/release"));
+        assert!(lib_host_command("", None, library, &[]).is_err());
+    }
+
+    #[test]
+    fn attach_pids() {
+        let processes = [
+            "    1 /sbin/launchd",
+            "  812 /ws/target/release/server",
+            "  977 /ws/target/debug/server",
+            " 1002 /usr/bin/server-cli",
+        ]
+        .join("\n");
+        let processes = processes.as_str();
+        let target = Path::new("/ws/target/release/server");
+        assert_eq!(matching_pids(processes, None, target), vec![812]);
+        assert_eq!(matching_pids(processes, Some("server"), target), vec![812, 977]);
+        assert_eq!(matching_pids(processes, Some("/usr/bin/server-cli"), target), vec![1002]);
+        assert!(matching_pids(processes, Some("postgres"), target).is_empty());
+        assert_eq!(find_attach_pid(Some("4242"), target).unwrap(), 4242);
+    }
+
+    #[test]
+    fn env_files() {
+        let contents = "# profiling\n\
+                        RUST_LOG=info\n\
+                        \n\
+                        export RAYON_NUM_THREADS = 4\n\
+                        GREETING=\"hello world\"\n";
+        let mut env = parse_env_file(contents).unwrap();
+        assert_eq!(
+            env,
+            vec![
+                ("RUST_LOG".to_owned(), "info".to_owned()),
+                ("RAYON_NUM_THREADS".to_owned(), "4".to_owned()),
+                ("GREETING".to_owned(), "hello world".to_owned()),
+            ]
+        );
+        assert!(parse_env_file("RUST_LOG").unwrap_err().to_string().contains("line 1"));
+
+        merge_env(&mut env, vec![("RUST_LOG".to_owned(), "debug".to_owned())]);
+        assert_eq!(env[0], ("RUST_LOG".to_owned(), "debug".to_owned()));
+        assert_eq!(env.len(), 3);
+    }
+
+    fn artifact(name: &str, kind: &str, test: bool) -> Artifact {
+        serde_json::from_value(serde_json::json!({
+            "package_id": "path+file:///ws/foo#0.1.0",
+            "target": { "name": name, "kind": [kind], "src_path": "/ws/foo/src/lib.rs" },
+            "profile": {
+                "opt_level": "3",
+                "debug_assertions": false,
+                "overflow_checks": false,
+                "test": test,
+            },
+            "features": [],
+            "filenames": [format!("/ws/target/release/deps/{name}-1234")],
+            "executable": format!("/ws/target/release/deps/{name}-1234"),
+            "fresh": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn bench_harnesses_of_libs_and_bins_are_skipped() {
+        let benches = [Target::AllBenches];
+        assert!(is_selected(&artifact("parse", "bench", true), &benches));
+        assert!(!is_selected(&artifact("foo", "lib", true), &benches));
+        assert!(!is_selected(&artifact("foo-cli", "bin", true), &benches));
+        // `cargo build --benches` also builds the bins themselves, for benches to run.
+        assert!(!is_selected(&artifact("foo-cli", "bin", false), &benches));
+        assert!(is_selected(&artifact("foo-cli", "bin", false), &[Target::AllBins]));
+        assert!(is_selected(&artifact("foo", "lib", true), &[Target::Lib]));
+        assert!(!is_selected(&artifact("foo-cli", "bin", false), &[Target::Test("it".into())]));
+        assert!(!is_selected(&artifact("foo-cli", "bin", false), &[Target::Bench("b".into())]));
+        assert!(is_selected(&artifact("foo-cli", "bin", false), &[Target::Default]));
+        assert!(is_selected(&artifact("foo-cli", "bin", false), &[Target::Bin("foo-cli".into())]));
+        assert!(!is_selected(&artifact("other", "bin", false), &[Target::Bin("foo-cli".into())]));
+        assert!(is_selected(&artifact("demo", "example", false), &[Target::AllExamples]));
+    }
+
+    #[test]
+    fn trace_summary() {
+        let root = Path::new("/ws");
+        let traces = vec![
+            ("foo (bin)".to_owned(), Some(PathBuf::from("/ws/target/instruments/foo.trace"))),
+            ("bar (example)".to_owned(), None),
+        ];
+        assert_eq!(
+            render_trace_summary(&traces, Some(root)),
+            "target         trace file\n\
+             -------------------------\n\
+             foo (bin)      target/instruments/foo.trace\n\
+             bar (example)  failed"
+        );
+    }
+
+    #[test]
+    fn codesign_follows_target() {
+        assert!(needs_codesign(Some("aarch64-apple-darwin")));
+        assert!(!needs_codesign(Some("x86_64-apple-darwin")));
+        assert_eq!(needs_codesign(None), cfg!(target_arch = "aarch64"));
+    }
+
+    #[test]
+    fn bench_args() {
+        assert_eq!(
+            bench_harness_args(BenchHarness::Libtest, None, Some(5000), &[]),
+            vec!["--bench"]
+        );
+        assert_eq!(
+            bench_harness_args(BenchHarness::Criterion, None, Some(2500), &[]),
+            vec!["--bench", "--profile-time", "2.5"]
+        );
+        assert_eq!(bench_harness_args(BenchHarness::Criterion, None, None, &[]), vec!["--bench"]);
+        let extra = vec!["--bench".to_owned(), "parse".to_owned()];
+        assert_eq!(
+            bench_harness_args(BenchHarness::Divan, None, None, &extra),
+            vec!["--bench", "parse"]
+        );
+        assert_eq!(
+            bench_harness_args(BenchHarness::Criterion, Some("group/case"), None, &[]),
+            vec!["group/case", "--exact", "--bench"]
+        );
+    }
+}
@@ -1,15 +1,23 @@
 //! interfacing with the `instruments` command line tool
 
+use std::collections::BTreeSet;
 use std::fmt::{Display, Write};
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Once};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
-use cargo::core::Workspace;
 use semver::Version;
 
 use crate::opt::AppConfig;
+use crate::shell::Shell;
+use crate::workspace::Workspace;
 
 /// Holds available templates.
 pub struct TemplateCatalog {
@@ -17,6 +25,109 @@ pub struct TemplateCatalog {
     custom_templates: Vec<String>,
 }
 
+/// What a recording is made of.
+enum Recording {
+    /// Launch the target and record it.
+    Launch,
+    /// Record the already running process with this PID.
+    Attach(u32),
+    /// Record every process on the system, while the target is launched
+    /// separately.
+    AllProcesses,
+}
+
+/// The working directory and redirections of the target's standard streams.
+///
+/// Paths are absolute, as the target may run in another directory.
+pub(crate) struct TargetIo {
+    cwd: Option<PathBuf>,
+    stdin: Option<PathBuf>,
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+    /// The terminal of our session, which the streams that are not redirected
+    /// use when xctrace launches the target.
+    tty: Option<PathBuf>,
+}
+
+impl TargetIo {
+    fn new(app_config: &AppConfig) -> Result<TargetIo> {
+        let current_dir = std::env::current_dir()?;
+        let absolute = |path: &Option<PathBuf>| path.as_ref().map(|path| current_dir.join(path));
+        Ok(TargetIo {
+            cwd: absolute(&app_config.cwd),
+            stdin: absolute(&app_config.stdin),
+            stdout: absolute(&app_config.stdout),
+            stderr: absolute(&app_config.stderr),
+            tty: get_tty()?.map(PathBuf::from),
+        })
+    }
+
+    /// Set the working directory and redirections on the `command` running
+    /// the target, or the tool launching it.
+    fn apply(&self, command: &mut Command) -> Result<()> {
+        if let Some(ref cwd) = self.cwd {
+            command.current_dir(cwd);
+        }
+        if let Some(ref stdin) = self.stdin {
+            command.stdin(open_file(stdin, false)?);
+        }
+        if let Some(ref stdout) = self.stdout {
+            command.stdout(open_file(stdout, true)?);
+        }
+        if let Some(ref stderr) = self.stderr {
+            command.stderr(open_file(stderr, true)?);
+        }
+        Ok(())
+    }
+}
+
+/// Open the file at `path` to read from, or create it to write to.
+fn open_file(path: &Path, write: bool) -> Result<File> {
+    match write {
+        true => File::create(path),
+        false => File::open(path),
+    }
+    .map_err(|e| anyhow!("failed to open {}: {}", path.display(), e))
+}
+
+/// What is recorded: a template, and any instruments on top of it.
+struct Instrumentation<'a> {
+    template_name: Option<&'a str>,
+    instruments: &'a [String],
+}
+
+impl<'a> Instrumentation<'a> {
+    fn new(app_config: &'a AppConfig) -> Instrumentation<'a> {
+        Instrumentation {
+            template_name: app_config.template_name.as_deref().map(resolve_template_name),
+            instruments: &app_config.instrument,
+        }
+    }
+
+    /// The name of the recording in trace file names, e.g. `Time Profiler`, or
+    /// `Allocations+Points of Interest` without a template.
+    fn name(&self) -> String {
+        match self.template_name {
+            Some(template_name) => template_name.to_owned(),
+            None => self.instruments.join("+"),
+        }
+    }
+}
+
+impl Display for Instrumentation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let instruments = self.instruments.iter().map(|name| format!("'{name}'"));
+        let instruments = instruments.collect::<Vec<_>>().join(", ");
+        match (self.template_name, instruments.is_empty()) {
+            (Some(template_name), true) => write!(f, "template '{template_name}'"),
+            (Some(template_name), false) => {
+                write!(f, "template '{template_name}' and instruments {instruments}")
+            }
+            (None, _) => write!(f, "instruments {instruments}"),
+        }
+    }
+}
+
 /// Represents the Xcode Instrument version detected.
 pub enum XcodeInstruments {
     XcTrace,
@@ -59,18 +170,52 @@ impl XcodeInstruments {
         }
     }
 
+    /// Check that each of `instruments` is available, according to
+    /// `xctrace list instruments`.
+    pub(crate) fn check_instruments(&self, instruments: &[String]) -> Result<()> {
+        if let XcodeInstruments::InstrumentsBinary = self {
+            return Err(anyhow!(
+                "--instrument requires xctrace, which comes with Xcode 12 on macOS 10.15 or later"
+            ));
+        }
+        let Output { status, stdout, stderr } =
+            Command::new("xcrun").args(["xctrace", "list", "instruments"]).output()?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Could not list instruments. Please check your Xcode Instruments installation."
+            ));
+        }
+        // Like templates, older versions of xctrace may list them on stderr.
+        let output = if stdout.is_empty() { stderr } else { stdout };
+        let available = parse_xctrace_instrument_list(std::str::from_utf8(&output)?);
+        match instruments.iter().find(|name| !available.contains(&name.as_str())) {
+            Some(name) => {
+                Err(anyhow!("unknown instrument '{}', see `xcrun xctrace list instruments`", name))
+            }
+            None => Ok(()),
+        }
+    }
+
     /// Prepare the Xcode Instruments profiling command
     ///
     /// If the `xctrace` tool is used, the prepared command looks like
     ///
     /// ```sh
     /// xcrun xctrace record --template MyTemplate \
+    ///                      --instrument MyInstrument \
     ///                      --time-limit 5000ms \
     ///                      --output path/to/tracefile \
     ///                      --launch \
     ///                      --
     /// ```
     ///
+    /// or ends with `--attach PID` or `--all-processes` instead of launching the
+    /// target, depending on the `recording`.
+    ///
+    /// The target's standard input and output are redirected to the files in
+    /// `io`, or else to the terminal. Without a terminal, its output goes to
+    /// our own standard output.
+    ///
     /// If the older `instruments` tool is used, the prepared command looks
     /// like
     ///
@@ -81,16 +226,32 @@ impl XcodeInstruments {
     /// ```
     fn profiling_command(
         &self,
-        template_name: &str,
+        instrumentation: &Instrumentation,
         trace_filepath: &Path,
         time_limit: Option<usize>,
+        env: &[(String, String)],
+        recording: &Recording,
+        io: &TargetIo,
     ) -> Result<Command> {
+        // Neither tool can redirect the standard error of the target it
+        // launches apart from its own, which must stay visible.
+        if io.stderr.is_some() && matches!(recording, Recording::Launch) {
+            return Err(anyhow!(
+                "--stderr requires --all-processes, as Instruments can't redirect the standard error of the target it launches"
+            ));
+        }
         match self {
             XcodeInstruments::XcTrace => {
                 let mut command = Command::new("xcrun");
                 command.args(["xctrace", "record"]);
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
 
-                command.args(["--template", template_name]);
+                if let Some(template_name) = instrumentation.template_name {
+                    command.args(["--template", template_name]);
+                }
+                for instrument in instrumentation.instruments {
+                    command.args(["--instrument", instrument]);
+                }
 
                 if let Some(limit_millis) = time_limit {
                     let limit_millis_str = format!("{}ms", limit_millis);
@@ -98,16 +259,58 @@ impl XcodeInstruments {
                 }
 
                 command.args(["--output", trace_filepath.to_str().unwrap()]);
-                // redirect stdin & err to the user's terminal
-                if let Some(tty) = get_tty()? {
-                    command.args(["--target-stdin", &tty, "--target-stdout", &tty]);
+                match recording {
+                    Recording::Launch => (),
+                    Recording::Attach(pid) => {
+                        command.args(["--attach", &pid.to_string()]);
+                        return Ok(command);
+                    }
+                    Recording::AllProcesses => {
+                        command.arg("--all-processes");
+                        return Ok(command);
+                    }
+                }
+
+                // redirect stdin & out to the given files, or the user's terminal
+                let tty = io.tty.as_ref();
+                if let Some(stdin) = io.stdin.as_ref().or(tty) {
+                    command.arg("--target-stdin").arg(stdin);
+                }
+                // without a terminal, the output of the target is streamed with ours
+                match io.stdout.as_ref().or(tty) {
+                    Some(stdout) => command.arg("--target-stdout").arg(stdout),
+                    None => command.args(["--target-stdout", "-"]),
+                };
+                if let Some(ref cwd) = io.cwd {
+                    command.current_dir(cwd);
+                }
+
+                for (name, value) in env {
+                    command.arg("--env").arg(format!("{name}={value}"));
                 }
 
                 command.args(["--launch", "--"]);
                 Ok(command)
             }
             XcodeInstruments::InstrumentsBinary => {
+                let flag = match recording {
+                    Recording::Launch => None,
+                    Recording::Attach(_) => Some("--attach"),
+                    Recording::AllProcesses => Some("--all-processes"),
+                };
+                if let Some(flag) = flag {
+                    return Err(anyhow!(
+                        "{flag} requires xctrace, which comes with Xcode 12 on macOS 10.15 or later"
+                    ));
+                }
+                let template_name = match instrumentation {
+                    Instrumentation { template_name: Some(template_name), instruments: [] } => {
+                        template_name
+                    }
+                    _ => return Err(anyhow!("--instrument requires xctrace")),
+                };
                 let mut command = Command::new("instruments");
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
                 command.args(["-t", template_name]);
 
                 command.arg("-D").arg(trace_filepath);
@@ -115,6 +318,11 @@ impl XcodeInstruments {
                 if let Some(limit) = time_limit {
                     command.args(["-l", &limit.to_string()]);
                 }
+
+                for (name, value) in env {
+                    command.arg("-e").arg(name).arg(value);
+                }
+                io.apply(&mut command)?;
                 Ok(command)
             }
         }
@@ -225,6 +433,12 @@ fn parse_xctrace_template_list() -> Result<TemplateCatalog> {
     Ok(TemplateCatalog { standard_templates, custom_templates })
 }
 
+/// Parse the output of `xctrace list instruments`, which has a header, e.g.
+/// `== Instruments ==`, followed by an instrument name per line.
+fn parse_xctrace_instrument_list(list: &str) -> Vec<&str> {
+    list.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('=')).collect()
+}
+
 /// Parse /usr/bin/instruments template list.
 ///
 /// The expected output on stdout is:
@@ -364,29 +578,24 @@ pub fn render_template_catalog(catalog: &TemplateCatalog) -> String {
 }
 
 /// Compute the tracefile output path, creating the directory structure
-/// in `target/instruments` if needed.
+/// in `trace_dir` if needed.
 fn prepare_trace_filepath(
-    target_filepath: &Path,
+    target_name: &str,
     template_name: &str,
     app_config: &AppConfig,
-    workspace_root: &Path,
+    trace_dir: &Path,
 ) -> Result<PathBuf> {
     if let Some(ref path) = app_config.trace_filepath {
         return Ok(path.to_path_buf());
     }
 
-    let trace_dir = workspace_root.join("target").join("instruments");
-
     if !trace_dir.exists() {
-        fs::create_dir_all(&trace_dir)
-            .map_err(|e| anyhow!("failed to create {:?}: {}", &trace_dir, e))?;
+        fs::create_dir_all(trace_dir)
+            .map_err(|e| anyhow!("failed to create {:?}: {}", trace_dir, e))?;
     }
 
     let trace_filename = {
-        let target_shortname = target_filepath
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .ok_or_else(|| anyhow!("invalid target path {:?}", target_filepath))?;
+        let target_shortname = target_name.replace("::", ".").replace(['/', ' '], "-");
         let template_name = template_name.replace(' ', "-");
         let now = chrono::Local::now();
 
@@ -420,74 +629,462 @@ fn abbrev_name(template_name: &str) -> Option<&str> {
     }
 }
 
-/// Profile the target binary at `binary_filepath`, write results at
-/// `trace_filepath` and returns its path.
+/// The file, inside the trace bundle, where the `TraceMetadata` is saved.
+const METADATA_FILENAME: &str = "cargo-instruments.json";
+
+/// Information about how a trace was recorded, saved inside the trace bundle.
+pub(crate) type TraceMetadata = serde_json::Map<String, serde_json::Value>;
+
+/// A single recording of a target binary.
+pub(crate) struct ProfileRun<'a> {
+    /// The binary to launch.
+    pub(crate) target_filepath: &'a Path,
+    /// Arguments passed to the binary.
+    pub(crate) target_args: &'a [String],
+    /// Environment variables to set for the binary.
+    pub(crate) env: Vec<(String, String)>,
+    /// The running process of the binary to attach to, instead of launching it.
+    pub(crate) attach_pid: Option<u32>,
+    /// The name of this run (e.g. the name of a single test), used to name
+    /// the trace file instead of the binary's name.
+    pub(crate) name: Option<&'a str>,
+    /// The directory to write the trace file to, unless an output path was given.
+    pub(crate) trace_dir: &'a Path,
+    /// Extra information to save with the trace.
+    pub(crate) metadata: TraceMetadata,
+}
+
+/// Profile the binary described by `run`, write results at `trace_filepath`
+/// and returns its path, along with how the binary exited, if that is known.
+///
+/// The binary doesn't have to belong to a cargo workspace; if it does, paths
+/// are shown relative to the workspace root.
 pub(crate) fn profile_target(
-    target_filepath: &Path,
+    run: &ProfileRun,
     xctrace_tool: &XcodeInstruments,
     app_config: &AppConfig,
-    workspace: &Workspace,
-) -> Result<PathBuf> {
-    // 1. Get the template name from config
-    // This borrows a ref to the String in Option<String>. The value can be
-    // unwrapped because in this version the template was checked earlier to
-    // be a `Some(x)`.
-    let template_name = resolve_template_name(app_config.template_name.as_deref().unwrap());
+    shell: &Shell,
+    workspace: Option<&Workspace>,
+) -> Result<(PathBuf, Option<TargetExit>)> {
+    let ProfileRun { target_filepath, target_args, name: run_name, trace_dir, .. } = *run;
+
+    // 1. Get the template and instruments from config
+    // At least one of them was given, as checked when parsing arguments.
+    let instrumentation = Instrumentation::new(app_config);
 
     // 2. Compute the trace filepath and create its parent directory
-    let workspace_root = workspace.root().to_path_buf();
-    let trace_filepath = prepare_trace_filepath(
-        target_filepath,
-        template_name,
-        app_config,
-        workspace_root.as_path(),
-    )?;
+    let target_name = match run_name {
+        Some(name) => name,
+        None => target_filepath
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("invalid target path {:?}", target_filepath))?,
+    };
+    let trace_filepath =
+        prepare_trace_filepath(target_name, &instrumentation.name(), app_config, trace_dir)?;
 
     // 3. Print current activity `Profiling target/debug/tries`
+    let target_shortpath = workspace
+        .and_then(|workspace| target_filepath.strip_prefix(workspace.root()).ok())
+        .unwrap_or(target_filepath)
+        .to_string_lossy();
     {
-        let target_shortpath = target_filepath
-            .strip_prefix(workspace_root)
-            .unwrap_or(target_filepath)
-            .to_string_lossy();
-        let status_detail = format!("{} with template '{}'", target_shortpath, template_name);
-        workspace.gctx().shell().status("Profiling", status_detail)?;
+        let mut status_detail = target_shortpath.to_string();
+        if let Some(name) = run_name {
+            write!(status_detail, " ({})", name)?;
+        }
+        if let Some(pid) = run.attach_pid {
+            write!(status_detail, " (pid {})", pid)?;
+        }
+        write!(status_detail, " with {}", instrumentation)?;
+        shell.status("Profiling", status_detail)?;
     }
 
-    let mut command =
-        xctrace_tool.profiling_command(template_name, &trace_filepath, app_config.time_limit)?;
-
-    command.arg(target_filepath);
+    let recording = match run.attach_pid {
+        Some(pid) => Recording::Attach(pid),
+        // rustc, build scripts and proc-macros are separate processes, which
+        // `--launch` would not record.
+        None if app_config.all_processes || app_config.profile_build => Recording::AllProcesses,
+        None => Recording::Launch,
+    };
+    // Relative paths would be resolved from the target's working directory.
+    let current_dir = std::env::current_dir()?;
+    let io = TargetIo::new(app_config)?;
+    let mut command = xctrace_tool.profiling_command(
+        &instrumentation,
+        &current_dir.join(&trace_filepath),
+        app_config.time_limit,
+        &run.env,
+        &recording,
+        &io,
+    )?;
 
-    if !app_config.target_args.is_empty() {
-        command.args(app_config.target_args.as_slice());
+    if let Recording::Launch = recording {
+        command.arg(current_dir.join(target_filepath));
+        command.args(target_args);
     }
 
     log_command_string(&command);
 
-    let output = command.output()?;
-    let stdout = if !output.stdout.is_empty() {
-        String::from_utf8_lossy(&output.stdout)
-    } else {
-        "{empty}".into()
+    let mut process_tree = BTreeSet::new();
+    let (output, target_status) = match recording {
+        Recording::AllProcesses => record_all_processes(command, run, &io, &mut process_tree)?,
+        _ => (Recorder::spawn(&mut command, None)?.wait()?, None),
     };
-    let stderr = if !output.stderr.is_empty() {
-        String::from_utf8_lossy(&output.stderr)
+    log::debug!("captured stdout:\n{}", String::from_utf8_lossy(&output.stdout));
+    log::debug!("captured stderr:\n{}", String::from_utf8_lossy(&output.stderr));
+
+    // Once interrupted, xctrace may not exit successfully, but its trace is
+    // still worth keeping.
+    if was_interrupted() {
+        wait_for_trace(&current_dir.join(&trace_filepath))?;
+    } else if output.status.success() {
+        log::debug!("{xctrace_tool} exited successfully");
     } else {
-        "{empty}".into()
+        return Err(anyhow!("{xctrace_tool} exited with error ({})", output.status));
+    }
+
+    // 4. Report how the target exited, which only xctrace records
+    let target_exit = match (target_status, xctrace_tool) {
+        (Some(status), _) => Some(TargetExit::from(status)),
+        (None, XcodeInstruments::XcTrace) => read_target_exit(&trace_filepath),
+        (None, XcodeInstruments::InstrumentsBinary) => None,
     };
-    if output.status.success() {
-        log::debug!("{xctrace_tool} exited successfully");
-        log::debug!("captured stdout:\n{stdout}");
-        log::debug!("captured stderr:\n{stderr}");
+    let mut recorded = TraceMetadata::new();
+    if !process_tree.is_empty() {
+        recorded.insert("process-tree".into(), process_tree.into_iter().collect());
+    }
+    if let Some(ref exit) = target_exit {
+        recorded.insert("target-exit-code".into(), exit.code.into());
+        if let Some(ref reason) = exit.reason {
+            recorded.insert("target-termination-reason".into(), reason.as_str().into());
+        }
+        if exit.success() {
+            shell.status("Exited", format!("{target_shortpath} with {exit}"))?;
+        } else {
+            shell.warn(format!("{target_shortpath} exited with {exit}"))?;
+        }
+    }
+
+    if let Some(template_name) = instrumentation.template_name {
+        recorded.insert("template".into(), template_name.into());
     }
+    if !instrumentation.instruments.is_empty() {
+        recorded.insert("instruments".into(), instrumentation.instruments.into());
+    }
+    write_trace_metadata(&trace_filepath, run, recorded)?;
+
+    Ok((trace_filepath, target_exit))
+}
+
+/// Run the recording `command` of every process, while the target of `run`
+/// is launched separately, and stop it once the target exits. If the
+/// recording stops first, e.g. after `--time-limit`, the target is killed.
+///
+/// The PIDs of the target and its descendants are added to `process_tree`.
+/// Each sample of the processes runs `ps`, which is recorded too, so they are
+/// only sampled every second, and short-lived processes may be missed.
+fn record_all_processes(
+    mut command: Command,
+    run: &ProfileRun,
+    io: &TargetIo,
+    process_tree: &mut BTreeSet<u32>,
+) -> Result<(Output, Option<ExitStatus>)> {
+    let (started_tx, started_rx) = mpsc::channel();
+    let mut recorder = Recorder::spawn(&mut command, Some(started_tx))?;
+    // Wait for the recording to start, so that the target is recorded from
+    // its launch, but not forever in case the output of xctrace is buffered.
+    let _ = started_rx.recv_timeout(Duration::from_secs(10));
+
+    let mut target_status = None;
+    if recorder.child.try_wait()?.is_none() {
+        let mut target = Command::new(std::env::current_dir()?.join(run.target_filepath));
+        target.args(run.target_args).envs(run.env.iter().map(|(name, value)| (name, value)));
+        io.apply(&mut target)?;
+        log_command_string(&target);
+        let mut target = target.spawn()?;
+        process_tree.insert(target.id());
+        let mut last_sample: Option<Instant> = None;
+        while !was_interrupted() {
+            target_status = target.try_wait()?;
+            if target_status.is_some() || recorder.child.try_wait()?.is_some() {
+                break;
+            }
+            if last_sample.is_none_or(|sample| sample.elapsed() >= PROCESS_SAMPLE_INTERVAL) {
+                let processes = Command::new("ps").args(["-axo", "pid=,ppid="]).output()?;
+                extend_process_tree(&String::from_utf8_lossy(&processes.stdout), process_tree);
+                last_sample = Some(Instant::now());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        // The recording may have stopped already, after `--time-limit` or
+        // when interrupted.
+        if recorder.child.try_wait()?.is_none() && !was_interrupted() {
+            interrupt(recorder.child.id())?;
+        }
+        // Nothing records the target anymore, so it doesn't get to finish.
+        if target_status.is_none() {
+            let _ = target.kill();
+            target.wait()?;
+        }
+    }
+
+    Ok((recorder.wait()?, target_status))
+}
+
+/// How often the process tree of the target is sampled, with `--all-processes`.
+const PROCESS_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the target of a recording exited.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TargetExit {
+    code: i32,
+    /// e.g. `exit(101)` or `Uncaught signal 6 (SIGABRT)`
+    reason: Option<String>,
+}
+
+impl TargetExit {
+    pub(crate) fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+impl From<ExitStatus> for TargetExit {
+    fn from(status: ExitStatus) -> TargetExit {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => TargetExit { code, reason: None },
+            (None, signal) => {
+                let signal = signal.unwrap_or_default();
+                TargetExit { code: 128 + signal, reason: Some(format!("signal {signal}")) }
+            }
+        }
+    }
+}
+
+impl Display for TargetExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "status {}", self.code)?;
+        match self.reason {
+            Some(ref reason) if *reason != format!("exit({})", self.code) => {
+                write!(f, " ({reason})")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Read how the target exited from the table of contents of the trace at
+/// `trace_filepath`, if xctrace recorded it.
+fn read_target_exit(trace_filepath: &Path) -> Option<TargetExit> {
+    let output = Command::new("xcrun")
+        .args(["xctrace", "export", "--toc", "--input"])
+        .arg(trace_filepath)
+        .output()
+        .ok()?;
     if !output.status.success() {
-        return Err(anyhow!(
-            "{xctrace_tool} exited with error.\nstdout: {}\nstderr: {stderr}",
-            stdout.trim_end(),
-        ));
+        log::debug!("failed to export the TOC: {}", String::from_utf8_lossy(&output.stderr));
+        return None;
     }
+    parse_target_exit(&String::from_utf8_lossy(&output.stdout))
+}
 
-    Ok(trace_filepath)
+/// Parse the exit status of the target process from a trace TOC, with an
+/// element like
+///
+/// ```xml
+/// <process type="launched" return-exit-status="101" termination-reason="exit(101)" .../>
+/// ```
+///
+/// The last run is used, if the trace has several.
+fn parse_target_exit(toc: &str) -> Option<TargetExit> {
+    let attribute = |element: &str, name: &str| {
+        let start = element.find(&format!(" {name}=\""))? + name.len() + 3;
+        let len = element[start..].find('"')?;
+        Some(element[start..start + len].to_owned())
+    };
+    toc.split("<process ")
+        .skip(1)
+        .filter_map(|element| {
+            let element = format!(" {}", &element[..element.find('>')?]);
+            let code = attribute(&element, "return-exit-status")?.parse().ok()?;
+            Some(TargetExit { code, reason: attribute(&element, "termination-reason") })
+        })
+        .last()
+}
+
+/// The PID of the running recorder, which Ctrl-C is forwarded to, or 0.
+static RECORDER_PID: AtomicU32 = AtomicU32::new(0);
+/// Whether a recording was interrupted with Ctrl-C (or SIGTERM).
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the user interrupted a recording, in which case nothing else should
+/// be profiled.
+pub(crate) fn was_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// A running `xctrace` or `instruments` command, whose output is streamed to
+/// ours as it comes, and kept for logging.
+struct Recorder {
+    child: Child,
+    stdout: Option<JoinHandle<String>>,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl Recorder {
+    /// Spawn the recording `command`. `started` is notified when the recording
+    /// has started, according to its output.
+    ///
+    /// On a terminal, the recorder stays in the foreground process group, so
+    /// that the target it launches can read from the terminal. Otherwise it
+    /// gets a process group of its own, see [`forward_interrupts`].
+    fn spawn(command: &mut Command, started: Option<mpsc::Sender<()>>) -> Result<Recorder> {
+        forward_interrupts();
+        if !on_terminal() {
+            command.process_group(0);
+        }
+        let mut child = command.spawn()?;
+        RECORDER_PID.store(child.id(), Ordering::SeqCst);
+        let stdout = child.stdout.take().map(|out| tee(out, std::io::stdout, started));
+        let stderr = child.stderr.take().map(|err| tee(err, std::io::stderr, None));
+        Ok(Recorder { child, stdout, stderr })
+    }
+
+    /// Wait for the recording to be over, returning its captured output.
+    fn wait(mut self) -> Result<Output> {
+        let status = self.child.wait();
+        RECORDER_PID.store(0, Ordering::SeqCst);
+        let captured = |reader: Option<JoinHandle<String>>| {
+            reader.and_then(|reader| reader.join().ok()).unwrap_or_default().into_bytes()
+        };
+        Ok(Output { status: status?, stdout: captured(self.stdout), stderr: captured(self.stderr) })
+    }
+}
+
+/// Copy each line of `stream` to `out` as it comes, in a thread returning all
+/// of them. `started` is notified when recording starts.
+fn tee<W: std::io::Write + 'static>(
+    stream: impl Read + Send + 'static,
+    out: fn() -> W,
+    started: Option<mpsc::Sender<()>>,
+) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut captured = String::new();
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if line.contains("Starting recording") {
+                if let Some(ref started) = started {
+                    let _ = started.send(());
+                }
+            }
+            let _ = writeln!(out(), "{line}");
+            captured.push_str(&line);
+            captured.push('\n');
+        }
+        captured
+    })
+}
+
+/// Stop the running recording on Ctrl-C or SIGTERM, instead of leaving its
+/// trace unfinished, and exit on those signals otherwise.
+///
+/// On a terminal, Ctrl-C and hangups already reach the recorder, which shares
+/// our process group, so nothing is forwarded, rather than a second SIGINT. Otherwise the recorder has a process group
+/// of its own, and only gets the SIGINT forwarded to it.
+fn forward_interrupts() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        let result = ctrlc::set_handler(|| match RECORDER_PID.load(Ordering::SeqCst) {
+            0 => std::process::exit(130),
+            pid => {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+                if on_terminal() {
+                    return;
+                }
+                if let Err(e) = interrupt(pid) {
+                    log::warn!("failed to stop recording: {e}");
+                }
+            }
+        });
+        if let Err(e) = result {
+            log::warn!("failed to handle Ctrl-C: {e}");
+        }
+    });
+}
+
+/// Whether we run on a terminal, whose signals reach the whole foreground
+/// process group.
+fn on_terminal() -> bool {
+    use std::io::IsTerminal;
+    std::io::stdin().is_terminal()
+}
+
+/// Send SIGINT to the process `pid`, which is how xctrace is asked to stop
+/// recording.
+fn interrupt(pid: u32) -> Result<()> {
+    Command::new("kill").args(["-INT", &pid.to_string()]).status()?;
+    Ok(())
+}
+
+/// Wait for the trace bundle at `trace_filepath` to be written, after the
+/// recording was interrupted.
+fn wait_for_trace(trace_filepath: &Path) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !trace_filepath.exists() {
+        if Instant::now() > deadline {
+            return Err(anyhow!("the trace was not saved at {}", trace_filepath.display()));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Add the descendants of the processes in `tree` to it, from the output of
+/// `ps -o pid=,ppid=`.
+fn extend_process_tree(processes: &str, tree: &mut BTreeSet<u32>) {
+    let processes = processes
+        .lines()
+        .filter_map(|line| {
+            let mut ids = line.split_whitespace().map(str::parse::<u32>);
+            Some((ids.next()?.ok()?, ids.next()?.ok()?))
+        })
+        .collect::<Vec<_>>();
+    // Parents may be listed after their children, so go until nothing is added.
+    loop {
+        let len = tree.len();
+        for &(pid, ppid) in &processes {
+            if tree.contains(&ppid) {
+                tree.insert(pid);
+            }
+        }
+        if tree.len() == len {
+            break;
+        }
+    }
+}
+
+/// Save the metadata of `run` inside the trace bundle at `trace_filepath`.
+///
+/// If the trace file contains several runs, this describes the latest one.
+fn write_trace_metadata(
+    trace_filepath: &Path,
+    run: &ProfileRun,
+    recorded: TraceMetadata,
+) -> Result<()> {
+    let mut metadata = TraceMetadata::new();
+    metadata.insert("cargo-instruments".into(), env!("CARGO_PKG_VERSION").into());
+    metadata.insert("target".into(), run.target_filepath.to_string_lossy().into());
+    metadata.insert("target-args".into(), run.target_args.into());
+    if !run.env.is_empty() {
+        let env = run.env.iter().map(|(name, value)| (name.clone(), value.as_str().into()));
+        metadata.insert("target-env".into(), TraceMetadata::from_iter(env).into());
+    }
+    metadata.extend(recorded);
+    metadata.extend(run.metadata.clone());
+
+    let metadata_filepath = trace_filepath.join(METADATA_FILENAME);
+    fs::write(&metadata_filepath, serde_json::to_string_pretty(&metadata)?)
+        .map_err(|e| anyhow!("failed to write {:?}: {}", metadata_filepath, e))
 }
 
 fn log_command_string(command: &Command) {
@@ -498,13 +1095,21 @@ fn log_command_string(command: &Command) {
 }
 
 /// get the tty of th current terminal session
+///
+/// `ps` shows `??` for a process without a controlling terminal, e.g. in CI.
 fn get_tty() -> Result<Option<String>> {
     let mut command = Command::new("ps");
     command.arg("otty=").arg(std::process::id().to_string());
-    Ok(String::from_utf8(command.output()?.stdout)?
+    Ok(parse_tty(&String::from_utf8(command.output()?.stdout)?))
+}
+
+/// Parse the output of `ps -o tty=` into the path of the terminal, if any.
+fn parse_tty(ps_output: &str) -> Option<String> {
+    ps_output
         .split_whitespace()
         .next()
-        .map(|tty| format!("/dev/{}", tty)))
+        .filter(|tty| !tty.starts_with('?'))
+        .map(|tty| format!("/dev/{}", tty))
 }
 
 impl Display for XcodeInstruments {
@@ -526,4 +1131,202 @@ mod test {
         assert_eq!(semver_from_utf8(b"11.1").unwrap(), Version::parse("11.1.0").unwrap());
         assert_eq!(semver_from_utf8(b"11").unwrap(), Version::parse("11.0.0").unwrap());
     }
+
+    #[test]
+    fn metadata_is_saved_in_trace() {
+        let trace_filepath = std::env::temp_dir()
+            .join(format!("cargo-instruments-test-{}.trace", std::process::id()));
+        fs::create_dir_all(&trace_filepath).unwrap();
+
+        let mut metadata = TraceMetadata::new();
+        metadata.insert("selected-by".into(), "default-run".into());
+        let run = ProfileRun {
+            target_filepath: Path::new("/ws/target/release/foo"),
+            target_args: &["--fast".to_owned()],
+            env: vec![("RUST_LOG".to_owned(), "debug".to_owned())],
+            attach_pid: None,
+            name: None,
+            trace_dir: Path::new("/ws/target/instruments"),
+            metadata,
+        };
+        let mut recorded = TraceMetadata::new();
+        recorded.insert("template".into(), "Time Profiler".into());
+        recorded.insert("process-tree".into(), serde_json::json!([812, 815]));
+        write_trace_metadata(&trace_filepath, &run, recorded).unwrap();
+
+        let saved = fs::read_to_string(trace_filepath.join(METADATA_FILENAME)).unwrap();
+        fs::remove_dir_all(&trace_filepath).unwrap();
+        let saved: TraceMetadata = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved["template"], "Time Profiler");
+        assert_eq!(saved["target"], "/ws/target/release/foo");
+        assert_eq!(saved["target-args"], serde_json::json!(["--fast"]));
+        assert_eq!(saved["target-env"], serde_json::json!({"RUST_LOG": "debug"}));
+        assert_eq!(saved["selected-by"], "default-run");
+        assert_eq!(saved["process-tree"], serde_json::json!([812, 815]));
+    }
+
+    #[test]
+    fn instrument_list() {
+        let list = "== Instruments ==\nActivity Monitor\nAllocations\nPoints of Interest\n\n";
+        assert_eq!(
+            parse_xctrace_instrument_list(list),
+            vec!["Activity Monitor", "Allocations", "Points of Interest"]
+        );
+    }
+
+    #[test]
+    fn instrumentation_names() {
+        let instruments = vec!["Allocations".to_owned(), "Points of Interest".to_owned()];
+        let both =
+            Instrumentation { template_name: Some("Time Profiler"), instruments: &instruments };
+        assert_eq!(both.name(), "Time Profiler");
+        assert_eq!(
+            both.to_string(),
+            "template 'Time Profiler' and instruments 'Allocations', 'Points of Interest'"
+        );
+        let only_instruments = Instrumentation { template_name: None, instruments: &instruments };
+        assert_eq!(only_instruments.name(), "Allocations+Points of Interest");
+        assert_eq!(only_instruments.to_string(), "instruments 'Allocations', 'Points of Interest'");
+    }
+
+    #[test]
+    fn target_io_defaults_to_the_terminal() {
+        let trace_filepath = Path::new("/ws/target/instruments/foo.trace");
+        let instrumentation =
+            Instrumentation { template_name: Some("Time Profiler"), instruments: &[] };
+        let args = |tty: Option<&str>| {
+            let io = TargetIo {
+                cwd: None,
+                stdin: None,
+                stdout: None,
+                stderr: None,
+                tty: tty.map(PathBuf::from),
+            };
+            let command = XcodeInstruments::XcTrace
+                .profiling_command(
+                    &instrumentation,
+                    trace_filepath,
+                    None,
+                    &[],
+                    &Recording::Launch,
+                    &io,
+                )
+                .unwrap();
+            command.get_args().map(|arg| arg.to_str().unwrap().to_owned()).collect::<Vec<_>>()
+        };
+
+        let with_tty = args(Some("/dev/ttys001"));
+        let stdin = with_tty.iter().position(|arg| arg == "--target-stdin").unwrap();
+        assert_eq!(with_tty[stdin + 1], "/dev/ttys001");
+        let stdout = with_tty.iter().position(|arg| arg == "--target-stdout").unwrap();
+        assert_eq!(with_tty[stdout + 1], "/dev/ttys001");
+
+        let without_tty = args(None);
+        assert!(!without_tty.iter().any(|arg| arg == "--target-stdin"));
+        let stdout = without_tty.iter().position(|arg| arg == "--target-stdout").unwrap();
+        assert_eq!(without_tty[stdout + 1], "-");
+
+        assert_eq!(parse_tty("ttys001\n"), Some("/dev/ttys001".to_owned()));
+        assert_eq!(parse_tty("??\n"), None);
+        assert_eq!(parse_tty(""), None);
+    }
+
+    #[test]
+    fn target_io_is_redirected() {
+        let io = TargetIo {
+            cwd: None,
+            stdin: Some("/data/in.txt".into()),
+            stdout: Some("/data/out.txt".into()),
+            stderr: None,
+            tty: Some("/dev/ttys001".into()),
+        };
+        let trace_filepath = Path::new("/ws/target/instruments/foo.trace");
+        let instrumentation =
+            Instrumentation { template_name: Some("Time Profiler"), instruments: &[] };
+        let command = XcodeInstruments::XcTrace
+            .profiling_command(&instrumentation, trace_filepath, None, &[], &Recording::Launch, &io)
+            .unwrap();
+        let args = command.get_args().map(|arg| arg.to_str().unwrap()).collect::<Vec<_>>();
+        let stdin = args.iter().position(|arg| *arg == "--target-stdin").unwrap();
+        assert_eq!(args[stdin + 1], "/data/in.txt");
+        let stdout = args.iter().position(|arg| *arg == "--target-stdout").unwrap();
+        assert_eq!(args[stdout + 1], "/data/out.txt");
+        assert_eq!(args[args.len() - 2..], ["--launch", "--"]);
+
+        let io = TargetIo { stderr: Some("/data/err.txt".into()), ..io };
+        for tool in [XcodeInstruments::XcTrace, XcodeInstruments::InstrumentsBinary] {
+            let command = tool.profiling_command(
+                &instrumentation,
+                trace_filepath,
+                None,
+                &[],
+                &Recording::Launch,
+                &io,
+            );
+            assert!(command.unwrap_err().to_string().contains("--all-processes"));
+        }
+        let command = XcodeInstruments::XcTrace
+            .profiling_command(
+                &instrumentation,
+                trace_filepath,
+                None,
+                &[],
+                &Recording::AllProcesses,
+                &io,
+            )
+            .unwrap();
+        assert!(command.get_args().any(|arg| arg == "--all-processes"));
+    }
+
+    #[test]
+    fn recorder_output_is_captured() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo Starting recording; echo oops >&2; exit 3"]);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let output = Recorder::spawn(&mut command, Some(started_tx)).unwrap().wait().unwrap();
+        assert!(started_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"Starting recording\n");
+        assert_eq!(output.stderr, b"oops\n");
+        assert_eq!(RECORDER_PID.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn target_exit_is_parsed() {
+        let toc = r#"<?xml version="1.0"?>
+<trace-toc>
+  <run number="1">
+    <info>
+      <target>
+        <device platform="macOS" model="MacBook Pro" name="mac" os-version="14.5 (23F79)"/>
+        <process type="launched" return-exit-status="0" termination-reason="exit(0)" name="foo" pid="812"/>
+      </target>
+    </info>
+  </run>
+  <run number="2">
+    <info>
+      <target>
+        <process type="launched" return-exit-status="134" termination-reason="Uncaught signal 6 (SIGABRT)" name="foo" pid="977"/>
+      </target>
+    </info>
+  </run>
+</trace-toc>"#;
+        let exit = parse_target_exit(toc).unwrap();
+        assert_eq!(exit.code, 134);
+        assert!(!exit.success());
+        assert_eq!(exit.to_string(), "status 134 (Uncaught signal 6 (SIGABRT))");
+        let first_run = &toc[..toc.find("<run number=\"2\">").unwrap()];
+        assert_eq!(parse_target_exit(first_run).unwrap().to_string(), "status 0");
+        assert_eq!(parse_target_exit("<trace-toc/>"), None);
+    }
+
+    #[test]
+    fn process_tree_is_followed() {
+        let processes =
+            "    1     0\n  900   812\n  812     1\n  815   812\n  901   900\n  902     1\n";
+        let mut tree = BTreeSet::from([812]);
+        extend_process_tree(processes, &mut tree);
+        assert_eq!(tree, BTreeSet::from([812, 815, 900, 901]));
+    }
 }
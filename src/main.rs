@@ -1,6 +1,8 @@
 mod app;
 mod instruments;
 mod opt;
+mod shell;
+mod workspace;
 
 #[cfg(not(target_os = "macos"))]
 compile_error!("cargo-instruments requires macOS.");
@@ -1,8 +1,7 @@
 //! CLI argument handling
 
 use anyhow::Result;
-use cargo::core::resolver::CliFeatures;
-use cargo::ops::Packages;
+use std::collections::BTreeSet;
 use std::fmt;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -34,27 +33,172 @@ pub(crate) struct AppConfig {
         short = "t",
         long = "template",
         value_name = "TEMPLATE",
-        required_unless = "list-templates"
+        required_unless_one = &["list-templates", "instrument"]
     )]
     pub(crate) template_name: Option<String>,
 
+    /// Record with the instrument NAME, with or without a template (may be given
+    /// several times)
+    ///
+    /// e.g. `--instrument Allocations --instrument "Points of Interest"`. To see
+    /// available instruments, run `xcrun xctrace list instruments`.
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    pub(crate) instrument: Vec<String>,
+
     /// Specify package for example/bin/bench
     ///
     /// For package that has only one bin, it's the same as `--bin PACKAGE_NAME`
     #[structopt(short = "p", long, value_name = "NAME")]
     package: Option<String>,
 
-    /// Example binary to run
-    #[structopt(long, group = "target", value_name = "NAME")]
-    example: Option<String>,
+    /// Build and profile the selected targets in every package in the workspace
+    ///
+    /// Traces are written to a directory of their own for each run.
+    #[structopt(long, conflicts_with = "package")]
+    pub(crate) workspace: bool,
+
+    /// Example binary to run (may be given several times)
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    example: Vec<String>,
+
+    /// Run all examples
+    #[structopt(long)]
+    examples: bool,
+
+    /// Binary to run (may be given several times)
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    bin: Vec<String>,
+
+    /// Run all binaries
+    #[structopt(long)]
+    bins: bool,
+
+    /// Benchmark target to run (may be given several times)
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    bench: Vec<String>,
 
-    /// Binary to run
-    #[structopt(long, group = "target", value_name = "NAME")]
-    bin: Option<String>,
+    /// Run all benchmarks
+    #[structopt(long)]
+    benches: bool,
 
-    /// Benchmark target to run
-    #[structopt(long, group = "target", value_name = "NAME")]
-    bench: Option<String>,
+    /// Only run the benchmark with this ID (requires `--bench`)
+    ///
+    /// The ID is checked against the list of benchmarks exposed by the bench
+    /// harness. If no ID is given, you will be prompted to pick one.
+    #[structopt(long, value_name = "ID", requires = "bench")]
+    pub(crate) bench_id: Option<Option<String>>,
+
+    /// Integration test target to run
+    #[structopt(
+        long,
+        group = "target",
+        value_name = "NAME",
+        conflicts_with_all = &["example", "examples", "bin", "bins", "bench", "benches"]
+    )]
+    test: Option<String>,
+
+    /// Run the library's unit test harness
+    #[structopt(
+        long,
+        group = "target",
+        conflicts_with_all = &["example", "examples", "bin", "bins", "bench", "benches"]
+    )]
+    lib: bool,
+
+    /// Build the library as a cdylib, and profile it loaded by COMMAND
+    ///
+    /// e.g. `--lib-host "python3 bench.py"`. The host is launched with
+    /// `DYLD_LIBRARY_PATH` and `DYLD_INSERT_LIBRARIES` pointing at the library
+    /// that was just built. Note that macOS drops `DYLD_*` variables when
+    /// launching system binaries, such as `/usr/bin/python3`.
+    #[structopt(
+        long,
+        group = "target",
+        value_name = "COMMAND",
+        conflicts_with_all = &["example", "examples", "bin", "bins", "bench", "benches"]
+    )]
+    pub(crate) lib_host: Option<String>,
+
+    /// Set VAR to the path of the library for `--lib-host`, instead of the
+    /// `DYLD_*` variables
+    #[structopt(long, value_name = "VAR", requires = "lib-host")]
+    pub(crate) lib_env: Option<String>,
+
+    /// Profile the executable at PATH instead of building a target
+    ///
+    /// The executable is used as is, e.g. a CI artifact, and does not need to
+    /// belong to a cargo workspace. Use `--test-name` or `--each-test` if it
+    /// is a test harness.
+    #[structopt(
+        long,
+        alias = "no-build",
+        value_name = "PATH",
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "target", "example", "examples", "bin", "bins", "bench", "benches", "package",
+            "workspace", "release", "profile", "profiling-profile", "frame-pointers",
+            "target-triple", "features", "all-features", "no-default-features",
+        ]
+    )]
+    pub(crate) exec: Option<PathBuf>,
+
+    /// Profile the build of the selected targets, instead of running them
+    ///
+    /// All processes are recorded while `cargo build` runs, so that the trace
+    /// shows where rustc, build scripts and proc-macros spend their time; the
+    /// PIDs of cargo's process tree are saved as `process-tree`, as with
+    /// `--all-processes`. Only what is out of date is compiled, so run
+    /// `cargo clean -p NAME` first to profile the build of a package from
+    /// scratch.
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "exec", "lib-host", "test-name", "each-test", "bench-id", "env", "env-file",
+        ]
+    )]
+    pub(crate) profile_build: bool,
+
+    /// Attach to a running process, by PID or name, instead of launching it
+    ///
+    /// Without a value, the process running the target that was just built is
+    /// looked for. Recording stops after `--time-limit`, when the process
+    /// exits, or on Ctrl-C.
+    #[structopt(
+        long,
+        value_name = "PID|NAME",
+        conflicts_with_all = &["lib-host", "profile-build", "test-name", "each-test", "bench-id"]
+    )]
+    pub(crate) attach: Option<Option<String>>,
+
+    /// Record every process on the system, while the target runs
+    ///
+    /// The target is launched separately, and the PIDs of its process tree,
+    /// e.g. worker subprocesses, are saved in the trace metadata as
+    /// `process-tree`, so that the trace can be filtered to them later.
+    #[structopt(long, conflicts_with = "attach")]
+    pub(crate) all_processes: bool,
+
+    /// Exit with an error if a profiled target exits with an error
+    ///
+    /// The trace is kept, and the target's exit status is always shown and
+    /// saved in the trace metadata; this makes e.g. CI notice the failure.
+    #[structopt(long)]
+    pub(crate) fail_on_target_error: bool,
+
+    /// Only run the test with this exact name (requires `--test`, `--lib` or `--exec`)
+    ///
+    /// Tests are run one at a time, with their output shown.
+    #[structopt(long, value_name = "NAME", conflicts_with = "lib-host")]
+    pub(crate) test_name: Option<String>,
+
+    /// Record a separate trace for each test (requires `--test`, `--lib` or `--exec`)
+    ///
+    /// The test harness is asked for the list of its tests, and each one is
+    /// profiled in turn. Trace files are named after the test, and are not
+    /// opened automatically. `#[ignore]` tests are skipped, unless
+    /// `--include-ignored` or `--ignored` is passed to the harness.
+    #[structopt(long, conflicts_with_all = &["test-name", "trace-filepath", "lib-host"])]
+    pub(crate) each_test: bool,
 
     /// Pass --release to cargo
     #[structopt(long, conflicts_with = "profile")]
@@ -64,9 +208,42 @@ pub(crate) struct AppConfig {
     #[structopt(long, value_name = "NAME")]
     profile: Option<String>,
 
+    /// Build with a `profiling` profile: `release`, with debug info
+    ///
+    /// The profile inherits from `release`, but always has line tables debug
+    /// info, packed into a .dSYM bundle. It is defined on the fly, so it does
+    /// not need to be added to Cargo.toml.
+    #[structopt(long, conflicts_with_all = &["release", "profile"])]
+    profiling_profile: bool,
+
+    /// Build with frame pointers, for more reliable call stacks
+    ///
+    /// `-C force-frame-pointers=yes` is added to any rustflags that are
+    /// already set. The build uses a separate target directory, so that the
+    /// regular build cache is left intact.
+    #[structopt(long)]
+    frame_pointers: bool,
+
+    /// Also rebuild the standard library with frame pointers (nightly only)
+    ///
+    /// This passes `-Z build-std` to cargo.
+    #[structopt(long, requires = "frame-pointers")]
+    build_std: bool,
+
+    /// Build for the target triple, e.g. `x86_64-apple-darwin`
+    ///
+    /// On Apple Silicon, x86_64 binaries are profiled under Rosetta.
+    #[structopt(long = "target", value_name = "TRIPLE")]
+    target_triple: Option<String>,
+
+    /// Directory for all generated artifacts, including trace files
+    #[structopt(long, value_name = "DIRECTORY", parse(from_os_str))]
+    target_dir: Option<PathBuf>,
+
     /// Output .trace file to the given path
     ///
-    /// Defaults to `target/instruments/{name}_{template-name}_{date}.trace`.
+    /// Defaults to `target/instruments/{name}_{template-name}_{date}.trace`,
+    /// in the target directory used for the build.
     ///
     /// If the file already exists, a new Run will be added.
     #[structopt(short = "o", long = "output", value_name = "PATH", parse(from_os_str))]
@@ -74,10 +251,62 @@ pub(crate) struct AppConfig {
 
     /// Limit recording time to the specified value (in milliseconds)
     ///
-    /// The program will be terminated after this limit is exceeded.
+    /// The program will be terminated after this limit is exceeded. Criterion
+    /// benchmarks are run with `--profile-time` set to the same duration.
     #[structopt(long, value_name = "MILLIS")]
     pub(crate) time_limit: Option<usize>,
 
+    /// Set an environment variable for the target (may be given several times)
+    ///
+    /// e.g. `--env RUST_LOG=debug`. Variables are only set for the target,
+    /// not for the build.
+    #[structopt(
+        long,
+        value_name = "KEY=VALUE",
+        number_of_values = 1,
+        parse(try_from_str = parse_env_var),
+        conflicts_with = "attach"
+    )]
+    pub(crate) env: Vec<(String, String)>,
+
+    /// Set the environment variables in FILE for the target (may be given
+    /// several times)
+    ///
+    /// The file has a `KEY=VALUE` entry per line, and may have blank lines and
+    /// `#` comments. Variables from `--env` take precedence.
+    #[structopt(
+        long,
+        value_name = "FILE",
+        number_of_values = 1,
+        parse(from_os_str),
+        conflicts_with = "attach"
+    )]
+    pub(crate) env_file: Vec<PathBuf>,
+
+    /// Run the target in DIR
+    #[structopt(long, value_name = "DIR", parse(from_os_str), conflicts_with = "attach")]
+    pub(crate) cwd: Option<PathBuf>,
+
+    /// Read the standard input of the target from FILE
+    ///
+    /// By default, the target reads from the terminal, if there is one.
+    #[structopt(long, value_name = "FILE", parse(from_os_str), conflicts_with = "attach")]
+    pub(crate) stdin: Option<PathBuf>,
+
+    /// Write the standard output of the target to FILE
+    ///
+    /// By default, the target writes to the terminal, or to our own standard
+    /// output when there is no terminal, e.g. in CI.
+    #[structopt(long, value_name = "FILE", parse(from_os_str), conflicts_with = "attach")]
+    pub(crate) stdout: Option<PathBuf>,
+
+    /// Write the standard error of the target to FILE (requires `--all-processes`)
+    ///
+    /// Instruments can't redirect the standard error of a target it launches
+    /// by itself, so the target must be launched separately.
+    #[structopt(long, value_name = "FILE", parse(from_os_str), conflicts_with = "attach")]
+    pub(crate) stderr: Option<PathBuf>,
+
     /// Open the generated .trace file after profiling
     ///
     /// The trace file will open in Xcode Instruments.
@@ -92,7 +321,11 @@ pub(crate) struct AppConfig {
     #[structopt(long, value_name = "CARGO-FEATURES")]
     pub(crate) features: Option<String>,
 
-    /// Path to Cargo.toml
+    /// Path to Cargo.toml, or to a single-file package
+    ///
+    /// Single-file packages, e.g. `bench.rs` with its manifest embedded in a
+    /// frontmatter, are built with `-Zscript`, which requires a nightly
+    /// toolchain.
     #[structopt(long, value_name = "PATH")]
     pub(crate) manifest_path: Option<PathBuf>,
 
@@ -104,6 +337,43 @@ pub(crate) struct AppConfig {
     #[structopt(long, display_order = 1001)]
     pub(crate) no_default_features: bool,
 
+    /// Override a configuration value, as with cargo's `--config KEY=VALUE`
+    #[structopt(long = "config", value_name = "KEY=VALUE", number_of_values = 1)]
+    config_overrides: Vec<String>,
+
+    /// Number of parallel jobs, defaults to # of CPUs
+    #[structopt(short = "j", long, value_name = "N", allow_hyphen_values = true)]
+    jobs: Option<String>,
+
+    /// Require Cargo.lock is up to date
+    #[structopt(long, display_order = 1002)]
+    locked: bool,
+
+    /// Require Cargo.lock and cache are up to date
+    #[structopt(long, display_order = 1002)]
+    frozen: bool,
+
+    /// Run without accessing the network
+    #[structopt(long, display_order = 1002)]
+    offline: bool,
+
+    /// Use verbose output (-vv very verbose/build.rs output)
+    #[structopt(short = "v", long, parse(from_occurrences), display_order = 1003)]
+    verbose: u32,
+
+    /// Do not print cargo log messages
+    #[structopt(short = "q", long, conflicts_with = "verbose", display_order = 1003)]
+    quiet: bool,
+
+    /// Coloring: auto, always, never
+    #[structopt(
+        long,
+        value_name = "WHEN",
+        possible_values = &["auto", "always", "never"],
+        display_order = 1003
+    )]
+    color: Option<String>,
+
     /// Arguments passed to the target binary.
     ///
     /// To pass flags, precede child args with `--`,
@@ -119,22 +389,22 @@ pub(crate) enum Target {
     Example(String),
     Bin(String),
     Bench(String),
+    Test(String),
+    Lib,
+    /// The library, built as a cdylib to be loaded by a host process.
+    Cdylib,
+    AllBins,
+    AllExamples,
+    AllBenches,
 }
 
 /// The package in which to look for the specified target (example/bin/bench)
 #[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Package {
     Default,
     Package(String),
-}
-
-impl From<Package> for Packages {
-    fn from(p: Package) -> Self {
-        match p {
-            Package::Default => Packages::Default,
-            Package::Package(s) => Packages::Packages(vec![s]),
-        }
-    }
+    All,
 }
 
 impl fmt::Display for Package {
@@ -144,6 +414,7 @@ impl fmt::Display for Package {
                 write!(f, "Default: search all packages for example/bin/bench")
             }
             Package::Package(s) => write!(f, "{}", s),
+            Package::All => write!(f, "All: every package in the workspace"),
         }
     }
 }
@@ -155,57 +426,184 @@ impl fmt::Display for Target {
             Target::Example(bin) => write!(f, "{bin} (example)",),
             Target::Bin(bin) => write!(f, "{bin} (bin)"),
             Target::Bench(bench) => write!(f, "{bench} (bench)"),
+            Target::Test(test) => write!(f, "{test} (test)"),
+            Target::Lib => write!(f, "lib (unit tests)"),
+            Target::Cdylib => write!(f, "lib (cdylib)"),
+            Target::AllBins => write!(f, "all binaries"),
+            Target::AllExamples => write!(f, "all examples"),
+            Target::AllBenches => write!(f, "all benchmarks"),
         }
     }
 }
 
+/// The features to enable, as given on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CliFeatures {
+    /// Either `feature` or `package/feature`.
+    pub(crate) features: BTreeSet<String>,
+    pub(crate) all_features: bool,
+    pub(crate) uses_default_features: bool,
+}
+
+impl CliFeatures {
+    /// Create from the arguments of `--features`, which, like cargo, may list
+    /// several features separated by spaces or commas.
+    pub(crate) fn from_command_line(
+        features: &[String],
+        all_features: bool,
+        uses_default_features: bool,
+    ) -> CliFeatures {
+        let features = features
+            .iter()
+            .flat_map(|s| s.split([' ', ',']))
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect();
+        CliFeatures { features, all_features, uses_default_features }
+    }
+}
+
+/// The name of the profile synthesized by `--profiling-profile`.
+const PROFILING_PROFILE: &str = "profiling";
+
 /// Cargo-specific options
 pub(crate) struct CargoOpts {
     pub(crate) package: Package,
-    pub(crate) target: Target,
+    pub(crate) targets: Vec<Target>,
     pub(crate) profile: String,
     pub(crate) features: CliFeatures,
+    pub(crate) target_triple: Option<String>,
+    pub(crate) target_dir: Option<PathBuf>,
+    /// Config overrides, in the format of cargo's `--config KEY=VALUE`.
+    pub(crate) config: Vec<String>,
+    /// Flags to add to the rustflags of the build.
+    pub(crate) rustflags: Vec<String>,
+    /// Unstable `-Z` flags for cargo.
+    pub(crate) unstable_flags: Vec<String>,
+    pub(crate) jobs: Option<String>,
+    pub(crate) locked: bool,
+    pub(crate) frozen: bool,
+    pub(crate) offline: bool,
+    pub(crate) verbose: u32,
+    pub(crate) quiet: bool,
+    pub(crate) color: Option<String>,
+    /// Whether to build in a target directory of its own, because the build
+    /// has different rustflags.
+    pub(crate) separate_target_dir: bool,
+}
+
+/// Parse a `KEY=VALUE` environment variable.
+pub(crate) fn parse_env_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, found {var:?}")),
+    }
 }
 
 impl AppConfig {
     pub(crate) fn to_cargo_opts(&self) -> Result<CargoOpts> {
         let package = self.get_package();
-        let target = self.get_target();
+        let targets = self.get_targets();
         let features = self.features.clone().map(|s| vec![s]).unwrap_or_default();
-        let features = CliFeatures::from_command_line(
-            &features,
-            self.all_features,
-            !self.no_default_features,
-        )?;
-        let profile = self
-            .profile
-            .clone()
-            .unwrap_or_else(|| (if self.release { "release" } else { "dev" }).to_owned());
-        Ok(CargoOpts { package, target, profile, features })
+        let features =
+            CliFeatures::from_command_line(&features, self.all_features, !self.no_default_features);
+        let profile = if self.profiling_profile {
+            PROFILING_PROFILE.to_owned()
+        } else {
+            self.profile
+                .clone()
+                .unwrap_or_else(|| (if self.release { "release" } else { "dev" }).to_owned())
+        };
+        let target_triple = self.target_triple.clone();
+        let mut config = Vec::new();
+        if self.profiling_profile {
+            config.extend(profiling_profile_config());
+        }
+        // Overrides from the command line win over the synthesized profile.
+        config.extend(self.config_overrides.iter().cloned());
+        let mut rustflags = Vec::new();
+        let mut unstable_flags = Vec::new();
+        if self.frame_pointers {
+            rustflags.push("-Cforce-frame-pointers=yes".to_owned());
+        }
+        if self.build_std {
+            unstable_flags.push("build-std".to_owned());
+        }
+        if self.manifest_path.as_deref().is_some_and(|path| path.extension() == Some("rs".as_ref()))
+        {
+            unstable_flags.push("script".to_owned());
+        }
+        Ok(CargoOpts {
+            package,
+            targets,
+            profile,
+            features,
+            target_triple,
+            target_dir: self.target_dir.clone(),
+            config,
+            rustflags,
+            unstable_flags,
+            jobs: self.jobs.clone(),
+            locked: self.locked,
+            frozen: self.frozen,
+            offline: self.offline,
+            verbose: self.verbose,
+            quiet: self.quiet,
+            color: self.color.clone(),
+            separate_target_dir: self.frame_pointers,
+        })
     }
 
     fn get_package(&self) -> Package {
         if let Some(ref package) = self.package {
             Package::Package(package.clone())
+        } else if self.workspace {
+            Package::All
         } else {
             Package::Default
         }
     }
 
-    // valid target: --example,  --bin, --bench
-    fn get_target(&self) -> Target {
-        if let Some(ref example) = self.example {
-            Target::Example(example.clone())
-        } else if let Some(ref bin) = self.bin {
-            Target::Bin(bin.clone())
-        } else if let Some(ref bench) = self.bench {
-            Target::Bench(bench.clone())
-        } else {
-            Target::Default
+    // valid targets: --example(s), --bin(s), --bench(es), --test, --lib, --lib-host
+    fn get_targets(&self) -> Vec<Target> {
+        let mut targets = Vec::new();
+        if self.bins {
+            targets.push(Target::AllBins);
+        }
+        targets.extend(self.bin.iter().cloned().map(Target::Bin));
+        if self.examples {
+            targets.push(Target::AllExamples);
         }
+        targets.extend(self.example.iter().cloned().map(Target::Example));
+        if self.benches {
+            targets.push(Target::AllBenches);
+        }
+        targets.extend(self.bench.iter().cloned().map(Target::Bench));
+        if let Some(ref test) = self.test {
+            targets.push(Target::Test(test.clone()));
+        }
+        if self.lib {
+            targets.push(Target::Lib);
+        }
+        if self.lib_host.is_some() {
+            targets.push(Target::Cdylib);
+        }
+        if targets.is_empty() {
+            targets.push(Target::Default);
+        }
+        targets
     }
 }
 
+/// Config overrides defining the profile used by `--profiling-profile`.
+fn profiling_profile_config() -> Vec<String> {
+    vec![
+        format!("profile.{PROFILING_PROFILE}.inherits=\"release\""),
+        format!("profile.{PROFILING_PROFILE}.debug=\"line-tables-only\""),
+        format!("profile.{PROFILING_PROFILE}.split-debuginfo=\"packed\""),
+    ]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -213,21 +611,105 @@ mod tests {
     #[test]
     fn defaults() {
         let opts = AppConfig::from_iter(&["instruments", "-t", "template"]);
-        assert!(opts.example.is_none());
-        assert!(opts.bin.is_none());
+        assert!(opts.example.is_empty());
+        assert!(opts.bin.is_empty());
         assert!(!opts.release);
         assert!(opts.trace_filepath.is_none());
         assert!(opts.package.is_none());
         assert!(opts.manifest_path.is_none());
+        assert!(opts.target_triple.is_none());
+        assert!(opts.target_dir.is_none());
+        assert!(opts.config_overrides.is_empty());
+        assert!(opts.jobs.is_none());
+        assert!(!opts.locked && !opts.frozen && !opts.offline);
+        assert_eq!(opts.verbose, 0);
+        assert!(!opts.quiet);
+        assert!(opts.color.is_none());
+    }
+
+    #[test]
+    fn cargo_flags() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--locked",
+            "--offline",
+            "-j4",
+            "-vv",
+            "--color",
+            "never",
+            "--config",
+            "profile.release.lto=true",
+        ]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert!(cargo_opts.locked && cargo_opts.offline && !cargo_opts.frozen);
+        assert_eq!(cargo_opts.jobs.as_deref(), Some("4"));
+        assert_eq!(cargo_opts.verbose, 2);
+        assert_eq!(cargo_opts.color.as_deref(), Some("never"));
+        assert_eq!(cargo_opts.config, vec!["profile.release.lto=true".to_owned()]);
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "-j", "-1", "-q"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.jobs.as_deref(), Some("-1"));
+        assert!(cargo_opts.quiet);
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--jobs", "default"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.jobs.as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn config_overrides_follow_profiling_profile() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--profiling-profile",
+            "--config",
+            "profile.profiling.debug=true",
+        ]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.config.last().unwrap(), "profile.profiling.debug=true");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be used with")]
+    fn quiet_conflicts_with_verbose() {
+        AppConfig::from_iter_safe(&["instruments", "-t", "time", "-q", "-v"]).unwrap();
+    }
+
+    #[test]
+    fn target_dir() {
+        let opts =
+            AppConfig::from_iter(&["instruments", "-t", "time", "--target-dir", "/shared/target"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.target_dir, Some(PathBuf::from("/shared/target")));
+    }
+
+    #[test]
+    fn target_triple() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--bin",
+            "bin_arg",
+            "--target",
+            "x86_64-apple-darwin",
+        ]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.targets, vec![Target::Bin("bin_arg".into())]);
+        assert_eq!(cargo_opts.target_triple.as_deref(), Some("x86_64-apple-darwin"));
     }
 
     #[test]
     fn package_is_given() {
         let opts =
             AppConfig::from_iter(&["instruments", "--package", "foo", "--template", "alloc"]);
-        assert!(opts.example.is_none());
-        assert!(opts.bin.is_none());
-        assert!(opts.bench.is_none());
+        assert!(opts.example.is_empty());
+        assert!(opts.bin.is_empty());
+        assert!(opts.bench.is_empty());
         assert_eq!(opts.package.unwrap().as_str(), "foo");
 
         let opts = AppConfig::from_iter(&[
@@ -239,28 +721,28 @@ mod tests {
             "--bin",
             "bin_arg",
         ]);
-        assert!(opts.example.is_none());
-        assert!(opts.bench.is_none());
-        assert_eq!(opts.bin.unwrap().as_str(), "bin_arg");
+        assert!(opts.example.is_empty());
+        assert!(opts.bench.is_empty());
+        assert_eq!(opts.bin, vec!["bin_arg"]);
         assert_eq!(opts.package.unwrap().as_str(), "foo");
     }
 
     #[test]
-    #[should_panic(expected = "cannot be used with one or more of the other")]
+    #[should_panic(expected = "cannot be used with")]
     fn group_is_exclusive() {
         let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--bin", "bin_arg"]);
-        assert!(opts.example.is_none());
-        assert_eq!(opts.bin.unwrap().as_str(), "bin_arg");
+        assert!(opts.example.is_empty());
+        assert_eq!(opts.bin, vec!["bin_arg"]);
 
         let opts =
             AppConfig::from_iter(&["instruments", "-t", "time", "--example", "example_binary"]);
-        assert!(opts.bin.is_none());
-        assert_eq!(opts.example.unwrap().as_str(), "example_binary");
+        assert!(opts.bin.is_empty());
+        assert_eq!(opts.example, vec!["example_binary"]);
         let _opts = AppConfig::from_iter_safe(&[
             "instruments",
             "-t",
             "time",
-            "--bin",
+            "--test",
             "thing",
             "--example",
             "other",
@@ -268,6 +750,96 @@ mod tests {
         .unwrap();
     }
 
+    #[test]
+    fn multiple_targets() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--bin",
+            "a",
+            "--bin",
+            "b",
+            "--example",
+            "c",
+            "--",
+            "arg",
+        ]);
+        assert_eq!(opts.target_args, vec!["arg"]);
+        assert_eq!(
+            opts.to_cargo_opts().unwrap().targets,
+            vec![Target::Bin("a".into()), Target::Bin("b".into()), Target::Example("c".into())]
+        );
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--bins", "--examples"]);
+        assert_eq!(
+            opts.to_cargo_opts().unwrap().targets,
+            vec![Target::AllBins, Target::AllExamples]
+        );
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time"]);
+        assert_eq!(opts.to_cargo_opts().unwrap().targets, vec![Target::Default]);
+    }
+
+    #[test]
+    fn profiling_profile() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--profiling-profile"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.profile, "profiling");
+        assert_eq!(
+            cargo_opts.config,
+            vec![
+                "profile.profiling.inherits=\"release\"",
+                "profile.profiling.debug=\"line-tables-only\"",
+                "profile.profiling.split-debuginfo=\"packed\"",
+            ]
+        );
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--release"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.profile, "release");
+        assert!(cargo_opts.config.is_empty());
+
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--release",
+            "--profiling-profile",
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn frame_pointers() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert!(cargo_opts.rustflags.is_empty());
+        assert!(!cargo_opts.separate_target_dir);
+
+        let opts =
+            AppConfig::from_iter(&["instruments", "-t", "time", "--frame-pointers", "--build-std"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.rustflags, vec!["-Cforce-frame-pointers=yes"]);
+        assert_eq!(cargo_opts.unstable_flags, vec!["build-std"]);
+        assert!(cargo_opts.separate_target_dir);
+
+        let res = AppConfig::from_iter_safe(&["instruments", "-t", "time", "--build-std"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn workspace_benches() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--workspace", "--benches"]);
+        let cargo_opts = opts.to_cargo_opts().unwrap();
+        assert_eq!(cargo_opts.package, Package::All);
+        assert_eq!(cargo_opts.targets, vec![Target::AllBenches]);
+
+        let res =
+            AppConfig::from_iter_safe(&["instruments", "-t", "time", "--workspace", "-p", "foo"]);
+        assert!(res.is_err());
+    }
+
     #[test]
     fn limit_millis() {
         let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--time-limit", "42000"]);
@@ -293,16 +865,10 @@ mod tests {
         ];
         let opts = AppConfig::from_iter(opts);
         assert_eq!(opts.template_name, Some("time".into()));
-        assert_eq!(opts.example, Some("hello".to_string()));
+        assert_eq!(opts.example, vec!["hello"]);
         assert_eq!(opts.features, Some("svg im".to_string()));
-        let features: Vec<_> = opts
-            .to_cargo_opts()
-            .unwrap()
-            .features
-            .features
-            .iter()
-            .map(|feat| feat.to_string())
-            .collect();
+        let features: Vec<_> =
+            opts.to_cargo_opts().unwrap().features.features.into_iter().collect();
         assert_eq!(features, vec!["im", "svg"]);
     }
 
@@ -324,6 +890,213 @@ mod tests {
         assert_eq!(opts.target_args, vec!["hi", "-h", "--bin"]);
     }
 
+    #[test]
+    fn test_targets() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--test",
+            "integration",
+            "--test-name",
+            "slow::path",
+        ]);
+        assert_eq!(opts.test_name, Some("slow::path".into()));
+        assert_eq!(opts.to_cargo_opts().unwrap().targets, vec![Target::Test("integration".into())]);
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--lib"]);
+        assert!(opts.test_name.is_none());
+        assert_eq!(opts.to_cargo_opts().unwrap().targets, vec![Target::Lib]);
+
+        let res =
+            AppConfig::from_iter_safe(&["instruments", "-t", "time", "--lib", "--bin", "thing"]);
+        assert!(res.is_err());
+
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--lib", "--each-test"]);
+        assert!(opts.each_test);
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--lib",
+            "--each-test",
+            "--test-name",
+            "foo",
+        ]);
+        assert!(res.is_err());
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--lib",
+            "--each-test",
+            "-o",
+            "out.trace",
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn bench_id() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--bench", "parse"]);
+        assert_eq!(opts.bench_id, None);
+        let opts =
+            AppConfig::from_iter(&["instruments", "-t", "time", "--bench", "parse", "--bench-id"]);
+        assert_eq!(opts.bench_id, Some(None));
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--bench",
+            "parse",
+            "--bench-id",
+            "group/case",
+        ]);
+        assert_eq!(opts.bench_id, Some(Some("group/case".into())));
+        let res = AppConfig::from_iter_safe(&["instruments", "-t", "time", "--bench-id", "x"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn profile_build() {
+        let opts =
+            AppConfig::from_iter(&["instruments", "-t", "time", "--profile-build", "--release"]);
+        assert!(opts.profile_build);
+        assert_eq!(opts.to_cargo_opts().unwrap().profile, "release");
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--profile-build",
+            "--exec",
+            "foo",
+        ]);
+        assert!(res.is_err());
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--profile-build",
+            "--env",
+            "RUST_LOG=debug",
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn attach() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--attach"]);
+        assert_eq!(opts.attach, Some(None));
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--attach", "4242"]);
+        assert_eq!(opts.attach, Some(Some("4242".into())));
+        let res =
+            AppConfig::from_iter_safe(&["instruments", "-t", "time", "--attach", "--each-test"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn env_vars() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--env",
+            "RUST_LOG=debug",
+            "--env",
+            "MallocStackLogging=",
+            "--env-file",
+            "profile.env",
+        ]);
+        assert_eq!(
+            opts.env,
+            vec![
+                ("RUST_LOG".to_owned(), "debug".to_owned()),
+                ("MallocStackLogging".to_owned(), "".to_owned())
+            ]
+        );
+        assert_eq!(opts.env_file, vec![PathBuf::from("profile.env")]);
+        let res = AppConfig::from_iter_safe(&["instruments", "-t", "time", "--env", "RUST_LOG"]);
+        assert!(res.is_err());
+        let res = AppConfig::from_iter_safe(&["instruments", "-t", "time", "--env", "=x"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn instruments() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "--instrument",
+            "Allocations",
+            "--instrument",
+            "Points of Interest",
+        ]);
+        assert_eq!(opts.template_name, None);
+        assert_eq!(opts.instrument, vec!["Allocations", "Points of Interest"]);
+        let opts =
+            AppConfig::from_iter(&["instruments", "-t", "time", "--instrument", "Allocations"]);
+        assert_eq!(opts.template_name, Some("time".into()));
+        assert!(AppConfig::from_iter_safe(&["instruments", "--bin", "foo"]).is_err());
+    }
+
+    #[test]
+    fn lib_host() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--lib-host",
+            "python3 bench.py",
+            "--lib-env",
+            "MY_LIB",
+        ]);
+        assert_eq!(opts.lib_host.as_deref(), Some("python3 bench.py"));
+        assert_eq!(opts.lib_env.as_deref(), Some("MY_LIB"));
+        assert_eq!(opts.to_cargo_opts().unwrap().targets, vec![Target::Cdylib]);
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--lib-host",
+            "node",
+            "--lib",
+        ]);
+        assert!(res.is_err());
+        let res = AppConfig::from_iter_safe(&[
+            "instruments",
+            "-t",
+            "time",
+            "--lib-host",
+            "node",
+            "--test-name",
+            "foo",
+        ]);
+        assert!(res.is_err());
+        let res = AppConfig::from_iter_safe(&["instruments", "-t", "time", "--lib-env", "MY_LIB"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn exec() {
+        let opts = AppConfig::from_iter(&[
+            "instruments",
+            "-t",
+            "time",
+            "--exec",
+            "artifacts/foo",
+            "--test-name",
+            "slow::path",
+        ]);
+        assert_eq!(opts.exec, Some(PathBuf::from("artifacts/foo")));
+        assert_eq!(opts.test_name, Some("slow::path".into()));
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--no-build", "foo"]);
+        assert_eq!(opts.exec, Some(PathBuf::from("foo")));
+        for conflicting in [&["--bin", "foo"][..], &["--lib"], &["--release"], &["-p", "foo"]] {
+            let mut args = vec!["instruments", "-t", "time", "--exec", "foo"];
+            args.extend(conflicting);
+            assert!(AppConfig::from_iter_safe(&args).is_err(), "{conflicting:?}");
+        }
+    }
+
     #[test]
     fn manifest_path() {
         let opts = AppConfig::from_iter(&[
@@ -333,10 +1106,18 @@ mod tests {
             "--template",
             "alloc",
         ]);
-        assert!(opts.example.is_none());
-        assert!(opts.bin.is_none());
-        assert!(opts.bench.is_none());
+        assert!(opts.example.is_empty());
+        assert!(opts.bin.is_empty());
+        assert!(opts.bench.is_empty());
         assert!(opts.package.is_none());
         assert_eq!(opts.manifest_path.unwrap(), PathBuf::from("/path/to/Cargo.toml"));
     }
+
+    #[test]
+    fn cargo_script() {
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time", "--manifest-path", "b.rs"]);
+        assert_eq!(opts.to_cargo_opts().unwrap().unstable_flags, vec!["script"]);
+        let opts = AppConfig::from_iter(&["instruments", "-t", "time"]);
+        assert!(opts.to_cargo_opts().unwrap().unstable_flags.is_empty());
+    }
 }
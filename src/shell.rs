@@ -0,0 +1,65 @@
+//! Printing status messages in the style of cargo
+
+use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+
+use anyhow::Result;
+
+/// Prints messages to stderr, formatted like cargo's own.
+pub(crate) struct Shell {
+    color: bool,
+    quiet: bool,
+}
+
+const BOLD_GREEN: &str = "\x1b[1;32m";
+const BOLD_YELLOW: &str = "\x1b[1;33m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+impl Shell {
+    /// Create a shell following cargo's `--color` (or `CARGO_TERM_COLOR`) and
+    /// `--quiet` options.
+    pub(crate) fn new(color: Option<&str>, quiet: bool) -> Shell {
+        let color =
+            match color.map(str::to_owned).or_else(|| std::env::var("CARGO_TERM_COLOR").ok()) {
+                Some(when) if when == "always" => true,
+                Some(when) if when == "never" => false,
+                _ => std::io::stderr().is_terminal(),
+            };
+        Shell { color, quiet }
+    }
+
+    /// Print a status line, such as `   Profiling target/release/foo`.
+    ///
+    /// Nothing is printed with `--quiet`.
+    pub(crate) fn status(&self, status: &str, message: impl Display) -> Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        let (style, reset) = self.style(BOLD_GREEN);
+        writeln!(std::io::stderr(), "{style}{status:>12}{reset} {message}")?;
+        Ok(())
+    }
+
+    pub(crate) fn warn(&self, message: impl Display) -> Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+        let (style, reset) = self.style(BOLD_YELLOW);
+        writeln!(std::io::stderr(), "{style}warning{reset}: {message}")?;
+        Ok(())
+    }
+
+    pub(crate) fn error(&self, message: impl Display) -> Result<()> {
+        let (style, reset) = self.style(BOLD_RED);
+        writeln!(std::io::stderr(), "{style}error{reset}: {message}")?;
+        Ok(())
+    }
+
+    fn style(&self, style: &'static str) -> (&'static str, &'static str) {
+        match self.color {
+            true => (style, RESET),
+            false => ("", ""),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Building targets with the user's own `cargo`
+
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Result};
+use cargo_metadata::{Artifact, Message, Metadata, MetadataCommand, Package, PackageId};
+
+use crate::opt::{self, CargoOpts, Target};
+
+/// The cargo workspace containing the targets to profile.
+pub(crate) struct Workspace {
+    /// The `cargo` executable, which is the one running us when invoked as
+    /// `cargo instruments`.
+    cargo: PathBuf,
+    metadata: Metadata,
+    manifest_path: Option<PathBuf>,
+    target_dir: PathBuf,
+}
+
+impl Workspace {
+    /// Load the workspace of the manifest at `manifest_path`, or of the current
+    /// directory, with `cargo metadata`.
+    pub(crate) fn new(
+        cargo_options: &CargoOpts,
+        manifest_path: Option<&Path>,
+    ) -> Result<Workspace> {
+        let cargo = std::env::var_os("CARGO").map(PathBuf::from).unwrap_or_else(|| "cargo".into());
+        let mut command = MetadataCommand::new();
+        command.cargo_path(&cargo).no_deps().other_options(global_args(cargo_options));
+        if let Some(path) = manifest_path {
+            command.manifest_path(path);
+        }
+        let metadata = command.exec().map_err(|e| match e {
+            cargo_metadata::Error::CargoMetadata { stderr } => {
+                anyhow!("{}", stderr.trim().trim_start_matches("error: "))
+            }
+            e => anyhow!("failed to run `cargo metadata`: {e}"),
+        })?;
+
+        // `cargo metadata` has no `--target-dir`, so it can't know about ours.
+        let target_dir = match cargo_options.target_dir {
+            Some(ref dir) => std::env::current_dir()?.join(dir),
+            None => metadata.target_directory.clone().into_std_path_buf(),
+        };
+
+        Ok(Workspace {
+            cargo,
+            metadata,
+            manifest_path: manifest_path.map(Path::to_owned),
+            target_dir,
+        })
+    }
+
+    /// The root directory of the workspace.
+    pub(crate) fn root(&self) -> &Path {
+        self.metadata.workspace_root.as_std_path()
+    }
+
+    /// The directory the targets are built in.
+    pub(crate) fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    pub(crate) fn set_target_dir(&mut self, target_dir: PathBuf) {
+        self.target_dir = target_dir;
+    }
+
+    /// All the packages in the workspace.
+    pub(crate) fn members(&self) -> Vec<&Package> {
+        self.metadata.workspace_packages()
+    }
+
+    /// The packages built when no package is selected.
+    pub(crate) fn default_members(&self) -> Vec<&Package> {
+        // Older versions of cargo don't report the default members.
+        if self.metadata.workspace_default_members.is_available() {
+            self.metadata.workspace_default_packages()
+        } else {
+            match self.metadata.root_package() {
+                Some(package) => vec![package],
+                None => self.members(),
+            }
+        }
+    }
+
+    /// The member of the workspace with the given ID.
+    pub(crate) fn package(&self, id: &PackageId) -> Result<&Package> {
+        self.members()
+            .into_iter()
+            .find(|package| package.id == *id)
+            .ok_or_else(|| anyhow!("package {} is not a member of the workspace", id))
+    }
+
+    /// Build the targets selected by `cargo_options`, returning the artifacts
+    /// of the workspace members which are executables or cdylibs.
+    ///
+    /// Cargo reports its progress and any compiler diagnostics on stderr as
+    /// usual, while we read the artifacts from its JSON messages.
+    pub(crate) fn build(&self, cargo_options: &CargoOpts) -> Result<Vec<Artifact>> {
+        let (cargo, args) = self.build_command(cargo_options);
+        let mut command = Command::new(cargo);
+        command.args(args).arg("--message-format=json-render-diagnostics");
+        command.stdout(Stdio::piped());
+        log::debug!("executing command {:?}", command);
+
+        let mut child = command.spawn().map_err(|e| anyhow!("failed to run cargo: {e}"))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut artifacts = Vec::new();
+        for message in Message::parse_stream(BufReader::new(stdout)) {
+            match message? {
+                Message::CompilerArtifact(artifact)
+                    if (artifact.executable.is_some() || artifact.target.is_cdylib())
+                        && self.metadata.workspace_members.contains(&artifact.package_id) =>
+                {
+                    artifacts.push(artifact)
+                }
+                // e.g. the output of build scripts with `-vv`
+                Message::TextLine(line) => println!("{line}"),
+                _ => (),
+            }
+        }
+
+        if !child.wait()?.success() {
+            return Err(anyhow!("could not build the targets to profile"));
+        }
+        Ok(artifacts)
+    }
+
+    /// The `cargo` executable and its arguments to build the targets selected
+    /// by `cargo_options` in this workspace.
+    pub(crate) fn build_command(&self, cargo_options: &CargoOpts) -> (&Path, Vec<String>) {
+        let mut args = build_args(cargo_options);
+        args.push(format!("--target-dir={}", self.target_dir.display()));
+        if let Some(ref path) = self.manifest_path {
+            args.push(format!("--manifest-path={}", path.display()));
+        }
+        (&self.cargo, args)
+    }
+}
+
+/// Arguments accepted by every cargo command.
+fn global_args(cargo_options: &CargoOpts) -> Vec<String> {
+    let mut args = Vec::new();
+    for config in &cargo_options.config {
+        args.push("--config".to_owned());
+        args.push(config.clone());
+    }
+    for flag in &cargo_options.unstable_flags {
+        args.push(format!("-Z{flag}"));
+    }
+    if cargo_options.locked {
+        args.push("--locked".to_owned());
+    }
+    if cargo_options.frozen {
+        args.push("--frozen".to_owned());
+    }
+    if cargo_options.offline {
+        args.push("--offline".to_owned());
+    }
+    if let Some(ref color) = cargo_options.color {
+        args.push(format!("--color={color}"));
+    }
+    args
+}
+
+/// The arguments to cargo to build the targets selected by `cargo_options`,
+/// other than the target directory, manifest path and message format.
+///
+/// Test targets are built like `cargo test` does, as a test harness.
+fn build_args(cargo_options: &CargoOpts) -> Vec<String> {
+    let profiles_tests =
+        cargo_options.targets.iter().any(|target| matches!(target, Target::Test(_) | Target::Lib));
+    let mut args = match profiles_tests {
+        true => vec!["test".to_owned(), "--no-run".to_owned()],
+        false => vec!["build".to_owned()],
+    };
+    args.extend(global_args(cargo_options));
+    if cargo_options.verbose > 0 {
+        args.push(format!("-{}", "v".repeat(cargo_options.verbose as usize)));
+    }
+    if cargo_options.quiet {
+        args.push("--quiet".to_owned());
+    }
+
+    args.push(format!("--profile={}", cargo_options.profile));
+    match cargo_options.package {
+        opt::Package::Default => (),
+        opt::Package::Package(ref package) => args.push(format!("--package={package}")),
+        opt::Package::All => args.push("--workspace".to_owned()),
+    }
+
+    let features = &cargo_options.features;
+    if !features.features.is_empty() {
+        let features = features.features.iter().cloned().collect::<Vec<_>>();
+        args.push(format!("--features={}", features.join(",")));
+    }
+    if features.all_features {
+        args.push("--all-features".to_owned());
+    }
+    if !features.uses_default_features {
+        args.push("--no-default-features".to_owned());
+    }
+
+    if let Some(ref triple) = cargo_options.target_triple {
+        args.push(format!("--target={triple}"));
+    }
+    if let Some(ref jobs) = cargo_options.jobs {
+        args.push(format!("--jobs={jobs}"));
+    }
+
+    for target in &cargo_options.targets {
+        match target {
+            Target::Default => (),
+            Target::Bin(bin) => args.push(format!("--bin={bin}")),
+            Target::AllBins => args.push("--bins".to_owned()),
+            Target::Example(example) => args.push(format!("--example={example}")),
+            Target::AllExamples => args.push("--examples".to_owned()),
+            Target::Bench(bench) => args.push(format!("--bench={bench}")),
+            Target::AllBenches => args.push("--benches".to_owned()),
+            Target::Test(test) => args.push(format!("--test={test}")),
+            Target::Lib | Target::Cdylib => args.push("--lib".to_owned()),
+        }
+    }
+    args
+}
+
+/// Return the release (e.g. `1.80.0-nightly`) and host triple of the rustc
+/// used by cargo.
+pub(crate) fn rustc_version() -> Result<(String, String)> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc).arg("-vV").output()?;
+    if !output.status.success() {
+        return Err(anyhow!("failed to run rustc: {}", String::from_utf8_lossy(&output.stderr)));
+    }
+    parse_rustc_version(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("unexpected output from `rustc -vV`"))
+}
+
+fn parse_rustc_version(output: &str) -> Option<(String, String)> {
+    let field = |name: &str| {
+        output.lines().find_map(|line| line.strip_prefix(name)).map(|value| value.trim().to_owned())
+    };
+    Some((field("release:")?, field("host:")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opt::AppConfig;
+    use structopt::StructOpt;
+
+    fn build_args_for(args: &[&str]) -> Vec<String> {
+        let mut argv = vec!["instruments", "-t", "time"];
+        argv.extend(args);
+        build_args(&AppConfig::from_iter(&argv).to_cargo_opts().unwrap())
+    }
+
+    #[test]
+    fn default_build_args() {
+        assert_eq!(build_args_for(&[]), vec!["build", "--profile=dev"]);
+    }
+
+    #[test]
+    fn cargo_options_are_forwarded() {
+        assert_eq!(
+            build_args_for(&[
+                "--release",
+                "-p",
+                "foo",
+                "--example",
+                "ex",
+                "--bench",
+                "parse",
+                "--features",
+                "svg im",
+                "--no-default-features",
+                "--target",
+                "x86_64-apple-darwin",
+                "--locked",
+                "-j4",
+                "-vv",
+                "--config",
+                "profile.release.lto=true",
+            ]),
+            vec![
+                "build",
+                "--config",
+                "profile.release.lto=true",
+                "--locked",
+                "-vv",
+                "--profile=release",
+                "--package=foo",
+                "--features=im,svg",
+                "--no-default-features",
+                "--target=x86_64-apple-darwin",
+                "--jobs=4",
+                "--example=ex",
+                "--bench=parse",
+            ]
+        );
+    }
+
+    #[test]
+    fn tests_are_built_like_cargo_test() {
+        assert_eq!(
+            build_args_for(&["--lib", "--workspace"]),
+            vec!["test", "--no-run", "--profile=dev", "--workspace", "--lib",]
+        );
+    }
+
+    #[test]
+    fn rustc_version_is_parsed() {
+        let output = "rustc 1.80.0-nightly (ada5e2c7b 2024-05-31)\n\
+                      binary: rustc\n\
+                      host: aarch64-apple-darwin\n\
+                      release: 1.80.0-nightly\n";
+        assert_eq!(
+            parse_rustc_version(output),
+            Some(("1.80.0-nightly".to_owned(), "aarch64-apple-darwin".to_owned()))
+        );
+        assert_eq!(parse_rustc_version("rustc 1.80.0"), None);
+    }
+}